@@ -0,0 +1,74 @@
+//! Benchmarks `LogPreprocessor::preprocess` against the sequential reference implementation
+//! with a patterns file of 80 patterns, most of which do not match a given line.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use railog::preprocessing::LogPreprocessor;
+use std::hint::black_box;
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+/// The number of site-specific patterns added to the built-in ones.
+const CUSTOM_PATTERNS: usize = 66;
+
+/// Writes a patterns file with all built-in patterns followed by site-specific keyword patterns.
+fn patterns_file() -> NamedTempFile {
+    let mut file = NamedTempFile::new().expect("temporary patterns file");
+    writeln!(file, "@builtin all").unwrap();
+    for index in 0..CUSTOM_PATTERNS {
+        writeln!(file, r"\bsvc{index}-[a-z]+\b :: <SERVICE{index}>").unwrap();
+    }
+    file
+}
+
+/// Generates log lines of a few common shapes.
+fn corpus() -> Vec<String> {
+    (0..1000)
+        .map(|i| match i % 5 {
+            0 => format!(
+                "Jan  5 10:{:02}:{:02} web-{} sshd[{}]: Accepted publickey for alice from 10.0.{}.1 port {}",
+                i % 60,
+                i % 59,
+                i % 7,
+                1000 + i,
+                i % 255,
+                40000 + i
+            ),
+            1 => format!("GET /api/v1/users/{i} 200 {} bytes in {}ms", i * 13, i % 97),
+            2 => format!("svc{}-worker finished job {}", i % 66, i),
+            3 => "connection closed by peer".to_string(),
+            _ => format!("Error: request {i} failed, retrying"),
+        })
+        .collect()
+}
+
+fn bench_preprocess(c: &mut Criterion) {
+    let file = patterns_file();
+    let preprocessor = LogPreprocessor::new(file.path().to_str().unwrap()).unwrap();
+    let lines = corpus();
+    for line in &lines {
+        assert_eq!(
+            preprocessor.preprocess(line),
+            preprocessor.preprocess_sequential(line)
+        );
+    }
+
+    let mut group = c.benchmark_group("preprocess");
+    group.bench_function("prefiltered", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(preprocessor.preprocess(black_box(line)));
+            }
+        })
+    });
+    group.bench_function("sequential", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(preprocessor.preprocess_sequential(black_box(line)));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_preprocess);
+criterion_main!(benches);
@@ -0,0 +1,404 @@
+use crate::embedding::Embedder;
+use anyhow::{Error as E, Result};
+use log::{debug, info, warn};
+use ndarray::Array2;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// The default location of the embedding cache.
+pub const DEFAULT_CACHE_FILE: &str = "embeddings.cache";
+
+/// The default size limit of the embedding cache in bytes.
+pub const DEFAULT_CACHE_MAX_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Identifies the cache file format.
+const CACHE_MAGIC: &[u8; 8] = b"RLGEMB01";
+
+/// The key of a cached embedding: a SHA-256 over the embedder fingerprint and the message.
+type CacheKey = [u8; 32];
+
+/// A cached embedding.
+struct CacheEntry {
+    /// The value of the access clock when the entry was last used.
+    last_used: u64,
+    vector: Vec<f32>,
+}
+
+/// The number of bytes the cache file header takes up: the magic, the clock and the count.
+const HEADER_SIZE: u64 = CACHE_MAGIC.len() as u64 + 16;
+
+/// The number of bytes an entry takes up in the cache file besides its vector.
+const ENTRY_HEADER_SIZE: u64 = 32 + 8 + 4;
+
+impl CacheEntry {
+    /// The number of bytes the entry takes up in the cache file.
+    fn size(&self) -> u64 {
+        ENTRY_HEADER_SIZE + 4 * self.vector.len() as u64
+    }
+}
+
+/// Wraps an `Embedder` with an on-disk cache of embeddings, keyed by the embedder fingerprint
+/// and the preprocessed message.
+///
+/// Only messages missing from the cache are passed to the wrapped embedder. The cache is kept in
+/// memory and written back by `flush`; when it grows beyond its size limit, the least recently
+/// used embeddings are evicted. Since the key includes the embedder fingerprint, one cache file
+/// can be shared by different embedders and models. Concurrent runs sharing a cache file do not
+/// corrupt it, but only the embeddings of the run that flushes last are kept.
+pub struct CachedEmbedder<M: Embedder> {
+    inner: M,
+    path: PathBuf,
+    max_bytes: u64,
+    entries: HashMap<CacheKey, CacheEntry>,
+    clock: u64,
+    dirty: bool,
+    hits: u64,
+    misses: u64,
+}
+
+impl<M: Embedder> CachedEmbedder<M> {
+    /// Wraps an embedder with the cache stored in a file, which is created on the first flush.
+    ///
+    /// An unreadable or corrupt cache file is ignored with a warning, since it can always be rebuilt.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The embedder computing embeddings missing from the cache.
+    /// * `path` - The path to the cache file.
+    /// * `max_bytes` - The maximum size of the cache file.
+    pub fn open(inner: M, path: &Path, max_bytes: u64) -> Result<Self> {
+        let mut cache = Self {
+            inner,
+            path: path.to_path_buf(),
+            max_bytes,
+            entries: HashMap::new(),
+            clock: 0,
+            dirty: false,
+            hits: 0,
+            misses: 0,
+        };
+        match File::open(path) {
+            Ok(file) => {
+                let file_size = file.metadata()?.len();
+                if let Err(e) = cache.read_entries(BufReader::new(file), file_size) {
+                    warn!(
+                        "Ignoring unreadable embedding cache {}: {e}",
+                        path.display()
+                    );
+                    cache.entries.clear();
+                    cache.clock = 0;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        debug!(
+            "Loaded {} cached embeddings from {}.",
+            cache.entries.len(),
+            path.display()
+        );
+        Ok(cache)
+    }
+
+    /// Reads the entries of a cache file.
+    ///
+    /// The entry count and vector lengths are checked against the file size before anything is
+    /// allocated, so that a truncated or corrupt file is rejected instead of exhausting memory.
+    fn read_entries(&mut self, mut reader: impl Read, file_size: u64) -> Result<()> {
+        let truncated = || E::msg("truncated embedding cache file");
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CACHE_MAGIC {
+            return Err(E::msg("not an embedding cache file"));
+        }
+        self.clock = read_u64(&mut reader)?;
+        let count = read_u64(&mut reader)?;
+        let mut remaining = file_size.checked_sub(HEADER_SIZE).ok_or_else(truncated)?;
+        if count > remaining / ENTRY_HEADER_SIZE {
+            return Err(truncated());
+        }
+        for _ in 0..count {
+            let mut key = [0u8; 32];
+            reader.read_exact(&mut key)?;
+            let last_used = read_u64(&mut reader)?;
+            let mut length = [0u8; 4];
+            reader.read_exact(&mut length)?;
+            let vector_size = 4 * u64::from(u32::from_le_bytes(length));
+            remaining = remaining
+                .checked_sub(ENTRY_HEADER_SIZE + vector_size)
+                .ok_or_else(truncated)?;
+            let mut bytes = vec![0u8; vector_size as usize];
+            reader.read_exact(&mut bytes)?;
+            let vector = bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            self.entries.insert(key, CacheEntry { last_used, vector });
+        }
+        Ok(())
+    }
+
+    /// Evicts the least recently used entries until the cache fits its size limit.
+    fn evict(&mut self) {
+        let mut total: u64 = HEADER_SIZE + self.entries.values().map(CacheEntry::size).sum::<u64>();
+        if total <= self.max_bytes {
+            return;
+        }
+        let mut by_age: Vec<(u64, CacheKey)> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.last_used, *key))
+            .collect();
+        by_age.sort_unstable();
+        let mut evicted = 0;
+        for (_, key) in by_age {
+            if total <= self.max_bytes {
+                break;
+            }
+            if let Some(entry) = self.entries.remove(&key) {
+                total -= entry.size();
+                evicted += 1;
+            }
+        }
+        debug!("Evicted {evicted} embeddings from the cache.");
+    }
+
+    /// Computes the cache key of a message.
+    fn key(&self, message: &str) -> CacheKey {
+        let mut hasher = Sha256::new();
+        for part in [self.inner.fingerprint().as_bytes(), message.as_bytes()] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+}
+
+impl<M: Embedder> Embedder for CachedEmbedder<M> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn fingerprint(&self) -> &str {
+        self.inner.fingerprint()
+    }
+
+    fn dimensions(&self) -> usize {
+        self.inner.dimensions()
+    }
+
+    /// Cached embeddings of the wrong length can only come from a corrupt cache, since the key
+    /// includes the embedder fingerprint. The whole cache is then discarded with a warning.
+    fn embed(&mut self, sentences: &[&str]) -> Result<Array2<f32>> {
+        let keys: Vec<CacheKey> = sentences.iter().map(|s| self.key(s)).collect();
+        let dimensions = self.inner.dimensions();
+        if keys
+            .iter()
+            .filter_map(|key| self.entries.get(key))
+            .any(|entry| entry.vector.len() != dimensions)
+        {
+            warn!(
+                "Discarding corrupt embedding cache {}: cached embeddings do not have {dimensions} \
+                 dimensions",
+                self.path.display()
+            );
+            self.entries.clear();
+        }
+        let mut queued = HashSet::new();
+        let (missing_keys, missing): (Vec<CacheKey>, Vec<&str>) = keys
+            .iter()
+            .zip(sentences)
+            .filter(|(key, _)| !self.entries.contains_key(*key) && queued.insert(**key))
+            .map(|(key, sentence)| (*key, *sentence))
+            .unzip();
+        self.hits += (sentences.len() - missing.len()) as u64;
+        self.misses += missing.len() as u64;
+
+        if !missing.is_empty() {
+            let embeddings = self.inner.embed(&missing)?;
+            for (key, row) in missing_keys.into_iter().zip(embeddings.outer_iter()) {
+                let vector = row.to_vec();
+                self.entries.insert(
+                    key,
+                    CacheEntry {
+                        last_used: 0,
+                        vector,
+                    },
+                );
+            }
+        }
+
+        self.clock += 1;
+        self.dirty = true;
+        let mut rows = Vec::new();
+        for key in &keys {
+            let entry = self
+                .entries
+                .get_mut(key)
+                .ok_or_else(|| E::msg("Embedding missing from the cache"))?;
+            entry.last_used = self.clock;
+            rows.extend_from_slice(&entry.vector);
+        }
+        Ok(Array2::from_shape_vec((keys.len(), dimensions), rows)?)
+    }
+
+    /// Writes the cache file if any embeddings were used, evicting old entries first.
+    ///
+    /// The file is written to a uniquely named temporary file in the same directory first and
+    /// then renamed, so that neither an interrupted write nor a concurrent flush leaves a corrupt
+    /// cache behind.
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()?;
+        if !self.dirty {
+            return Ok(());
+        }
+        self.evict();
+        let dir = self
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let mut temp_file = NamedTempFile::new_in(dir)?;
+        let mut writer = BufWriter::new(&mut temp_file);
+        writer.write_all(CACHE_MAGIC)?;
+        writer.write_all(&self.clock.to_le_bytes())?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for (key, entry) in &self.entries {
+            writer.write_all(key)?;
+            writer.write_all(&entry.last_used.to_le_bytes())?;
+            writer.write_all(&(entry.vector.len() as u32).to_le_bytes())?;
+            for value in &entry.vector {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        temp_file.persist(&self.path)?;
+        self.dirty = false;
+        info!(
+            "Embedding cache: {} hits, {} misses, {} entries in {}.",
+            self.hits,
+            self.misses,
+            self.entries.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Reads a little-endian `u64`.
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::HashingEmbedder;
+    use tempfile::tempdir;
+
+    /// Counts the messages passed to the wrapped embedder.
+    struct CountingEmbedder {
+        inner: HashingEmbedder,
+        embedded: usize,
+    }
+
+    impl Embedder for CountingEmbedder {
+        fn name(&self) -> &str {
+            self.inner.name()
+        }
+
+        fn fingerprint(&self) -> &str {
+            self.inner.fingerprint()
+        }
+
+        fn dimensions(&self) -> usize {
+            self.inner.dimensions()
+        }
+
+        fn embed(&mut self, sentences: &[&str]) -> Result<Array2<f32>> {
+            self.embedded += sentences.len();
+            self.inner.embed(sentences)
+        }
+    }
+
+    fn counting() -> Result<CountingEmbedder> {
+        Ok(CountingEmbedder {
+            inner: HashingEmbedder::new(8)?,
+            embedded: 0,
+        })
+    }
+
+    #[test]
+    fn test_cache_skips_known_messages_and_evicts_old_ones() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("embeddings.cache");
+        let entry_size = 32 + 8 + 4 + 4 * 8;
+
+        let mut cache = CachedEmbedder::open(counting()?, &path, 1 << 20)?;
+        let first = cache.embed(&["disk full", "user login", "disk full"])?;
+        assert_eq!(cache.inner.embedded, 2);
+        assert_eq!(
+            first,
+            HashingEmbedder::new(8)?.embed(&["disk full", "user login", "disk full"])?
+        );
+        cache.flush()?;
+
+        // A later run only embeds new messages, and keeps the two most recently used ones.
+        let mut cache = CachedEmbedder::open(counting()?, &path, 24 + 2 * entry_size)?;
+        let second = cache.embed(&["user login", "disk error"])?;
+        assert_eq!(cache.inner.embedded, 1);
+        assert_eq!(second.row(0), first.row(1));
+        cache.flush()?;
+
+        let mut cache = CachedEmbedder::open(counting()?, &path, 1 << 20)?;
+        assert_eq!(cache.entries.len(), 2);
+        cache.embed(&["disk full", "disk error"])?;
+        assert_eq!(cache.inner.embedded, 1);
+
+        // A corrupt cache is ignored.
+        std::fs::write(&path, b"garbage")?;
+        let mut cache = CachedEmbedder::open(counting()?, &path, 1 << 20)?;
+        cache.embed(&["disk full"])?;
+        assert_eq!(cache.inner.embedded, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_rejects_lengths_beyond_the_file_size() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("embeddings.cache");
+        let mut cache = CachedEmbedder::open(counting()?, &path, 1 << 20)?;
+        cache.embed(&["disk full", "user login"])?;
+        cache.flush()?;
+        let valid = std::fs::read(&path)?;
+
+        // A huge entry count, a huge vector length and a truncated file all start empty.
+        let mut huge_count = valid.clone();
+        huge_count[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut huge_length = valid.clone();
+        huge_length[24 + 40..24 + 44].copy_from_slice(&u32::MAX.to_le_bytes());
+        let truncated = valid[..valid.len() - 1].to_vec();
+        for bytes in [huge_count, huge_length, truncated] {
+            std::fs::write(&path, bytes)?;
+            let cache = CachedEmbedder::open(counting()?, &path, 1 << 20)?;
+            assert!(cache.entries.is_empty());
+        }
+
+        // An entry of the wrong length for the embedder discards the cache.
+        std::fs::write(&path, &valid)?;
+        let mut cache = CachedEmbedder::open(counting()?, &path, 1 << 20)?;
+        let key = cache.key("disk full");
+        cache.entries.get_mut(&key).unwrap().vector.pop();
+        let embeddings = cache.embed(&["disk full"])?;
+        assert_eq!(cache.inner.embedded, 1);
+        assert_eq!(embeddings.ncols(), 8);
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+use crate::input::{line_hash, FileId, InputPosition};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// Returns the default checkpoint file for a centroids file, e.g. `centroids.checkpoint.json`
+/// for `centroids.json`.
+///
+/// # Arguments
+///
+/// * `centroids_file` - The path to the centroids file.
+pub fn default_checkpoint_file(centroids_file: &str) -> String {
+    Path::new(centroids_file)
+        .with_extension("checkpoint.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// How far an input has been ingested.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputCheckpoint {
+    /// The position just past the last ingested line.
+    pub position: InputPosition,
+    /// When the checkpoint was written.
+    pub updated_at: DateTime<Utc>,
+}
+
+/// The checkpoints of all inputs ingested into a model, keyed by canonical input path.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CheckpointStore {
+    inputs: BTreeMap<String, InputCheckpoint>,
+}
+
+impl CheckpointStore {
+    /// Loads the checkpoints from a file, or returns an empty store if it does not exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the checkpoint file.
+    pub fn load(path: &str) -> Result<Self> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Saves the checkpoints to a file in JSON format.
+    ///
+    /// The file is written to a uniquely named temporary file in the same directory first and
+    /// then renamed, so that neither an interrupted save nor a concurrent one leaves a corrupt
+    /// checkpoint behind.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the checkpoint file.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let dir = Path::new(path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let mut temp_file = NamedTempFile::new_in(dir)?;
+        let mut writer = BufWriter::new(&mut temp_file);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        drop(writer);
+        temp_file.persist(path)?;
+        Ok(())
+    }
+
+    /// Records how far an input has been read.
+    ///
+    /// # Arguments
+    ///
+    /// * `input_file` - The path to the log file.
+    /// * `position` - The position just past the last ingested line.
+    pub fn record(&mut self, input_file: &str, position: InputPosition) {
+        self.inputs.insert(
+            input_key(input_file),
+            InputCheckpoint {
+                position,
+                updated_at: Utc::now(),
+            },
+        );
+    }
+
+    /// Returns the position to resume reading an input from, or `None` to read it from the
+    /// beginning.
+    ///
+    /// The saved position is only used if the file is still the same file (same device and
+    /// inode), has not shrunk, and still contains the last ingested line at the saved offset.
+    ///
+    /// # Arguments
+    ///
+    /// * `input_file` - The path to the log file.
+    pub fn resume_position(&self, input_file: &str) -> Result<Option<InputPosition>> {
+        let Some(checkpoint) = self.inputs.get(&input_key(input_file)) else {
+            info!("No checkpoint for {input_file}, reading from the beginning.");
+            return Ok(None);
+        };
+        let position = &checkpoint.position;
+        let mut file = File::open(input_file)?;
+        let metadata = file.metadata()?;
+
+        if position.file_id.is_some() && FileId::of(&metadata) != position.file_id {
+            info!("{input_file} was replaced since the last run, reading from the beginning.");
+            return Ok(None);
+        }
+        if metadata.len() < position.offset {
+            warn!("{input_file} was truncated since the last run, reading from the beginning.");
+            return Ok(None);
+        }
+        if let Some(hash) = &position.last_line_hash {
+            let length = position.offset.saturating_sub(position.last_line_offset);
+            let mut bytes = Vec::new();
+            file.seek(SeekFrom::Start(position.last_line_offset))?;
+            file.take(length).read_to_end(&mut bytes)?;
+            let line = String::from_utf8_lossy(&bytes);
+            if line_hash(line.trim_end_matches(['\r', '\n'])) != *hash {
+                warn!("{input_file} was rewritten since the last run, reading from the beginning.");
+                return Ok(None);
+            }
+        }
+        info!(
+            "Resuming {input_file} at byte {} (checkpoint from {}).",
+            position.offset, checkpoint.updated_at
+        );
+        Ok(Some(position.clone()))
+    }
+}
+
+/// Returns the key under which the checkpoint of an input is stored.
+fn input_key(input_file: &str) -> String {
+    std::fs::canonicalize(input_file)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| input_file.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{FollowReader, Poll};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn read_all(path: &str, store: &CheckpointStore) -> Result<(Vec<String>, InputPosition)> {
+        let mut reader = match store.resume_position(path)? {
+            Some(position) => FollowReader::resume(path, &position, Duration::ZERO)?,
+            None => FollowReader::open(path, Duration::ZERO)?,
+        };
+        let mut lines = Vec::new();
+        while let Poll::Line(line) = reader.poll_line()? {
+            lines.push(line);
+        }
+        Ok((lines, reader.position().unwrap()))
+    }
+
+    #[test]
+    fn test_resume_where_previous_run_stopped() -> Result<()> {
+        let dir = tempdir()?;
+        let log = dir.path().join("app.log");
+        let log = log.to_str().unwrap();
+        let mut store = CheckpointStore::default();
+
+        std::fs::write(log, "a\nb\n")?;
+        let (lines, position) = read_all(log, &store)?;
+        assert_eq!(lines, ["a", "b"]);
+        store.record(log, position);
+
+        let checkpoint_file =
+            default_checkpoint_file(dir.path().join("centroids.json").to_str().unwrap());
+        assert!(checkpoint_file.ends_with("centroids.checkpoint.json"));
+        store.save(&checkpoint_file)?;
+        let store = CheckpointStore::load(&checkpoint_file)?;
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(log)?
+            .write_all(b"c\n")?;
+        let (lines, _) = read_all(log, &store)?;
+        assert_eq!(lines, ["c"]);
+
+        // Rewritten in place: the last ingested line is no longer at the saved offset.
+        std::fs::write(log, "x\ny\nz\n")?;
+        let (lines, _) = read_all(log, &store)?;
+        assert_eq!(lines, ["x", "y", "z"]);
+        Ok(())
+    }
+}
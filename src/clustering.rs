@@ -0,0 +1,662 @@
+use anyhow::{Error as E, Result};
+use log::debug;
+use ndarray::{Array1, Array2, ArrayView1};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The algorithm `train` uses to group log messages into clusters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClusteringAlgorithm {
+    /// Density-based clustering with a single neighbourhood radius, epsilon.
+    #[default]
+    Dbscan,
+    /// Hierarchical density-based clustering, which finds clusters of varying density without
+    /// an epsilon.
+    Hdbscan,
+    /// Single-pass online clustering: each message joins the nearest cluster within epsilon of
+    /// its running centroid, or starts a new one.
+    Leader,
+}
+
+impl FromStr for ClusteringAlgorithm {
+    type Err = E;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "dbscan" => Ok(Self::Dbscan),
+            "hdbscan" => Ok(Self::Hdbscan),
+            "leader" | "online" => Ok(Self::Leader),
+            _ => Err(E::msg(format!(
+                "Unknown clustering algorithm '{name}', expected dbscan, hdbscan or leader"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ClusteringAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dbscan => "dbscan",
+            Self::Hdbscan => "hdbscan",
+            Self::Leader => "leader",
+        })
+    }
+}
+
+/// The Euclidean distance between two embeddings.
+fn euclidean(a: ArrayView1<f32>, b: ArrayView1<f32>) -> f32 {
+    match (a.as_slice(), b.as_slice()) {
+        (Some(a), Some(b)) => {
+            // Independent partial sums, so that the compiler can vectorize the loop.
+            let mut sums = [0.0f32; 8];
+            let chunks = a.chunks_exact(8).zip(b.chunks_exact(8));
+            for (x, y) in chunks {
+                for lane in 0..8 {
+                    let d = x[lane] - y[lane];
+                    sums[lane] += d * d;
+                }
+            }
+            let tail = a.len() - a.len() % 8;
+            let rest: f32 = a[tail..]
+                .iter()
+                .zip(&b[tail..])
+                .map(|(x, y)| (x - y) * (x - y))
+                .sum();
+            (sums.iter().sum::<f32>() + rest).sqrt()
+        }
+        _ => a
+            .iter()
+            .zip(b.iter())
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum::<f32>()
+            .sqrt(),
+    }
+}
+
+/// A node of a `VpTree`.
+struct VpNode {
+    /// The vantage point, a row of the point matrix.
+    point: usize,
+    /// The median distance from the vantage point to the points below it.
+    radius: f32,
+    /// The subtree of points at most `radius` away from the vantage point.
+    inside: Option<usize>,
+    /// The subtree of points at least `radius` away from the vantage point.
+    outside: Option<usize>,
+}
+
+/// A vantage-point tree over the rows of a matrix, answering range queries under the Euclidean
+/// distance.
+///
+/// Each node splits the points below it at their median distance from a vantage point, so the
+/// triangle inequality rules out whole subtrees during a query. Unlike a k-d tree, it does not
+/// split along coordinate axes and keeps working in the hundreds of dimensions of an embedding.
+/// It takes linear memory and does not copy the points.
+pub struct VpTree<'a> {
+    points: &'a Array2<f32>,
+    nodes: Vec<VpNode>,
+    root: Option<usize>,
+}
+
+impl<'a> VpTree<'a> {
+    /// Builds the tree over all rows of a matrix.
+    ///
+    /// # Arguments
+    ///
+    /// * `points` - The points, one per row.
+    pub fn new(points: &'a Array2<f32>) -> Self {
+        let mut tree = Self {
+            points,
+            nodes: Vec::with_capacity(points.nrows()),
+            root: None,
+        };
+        let mut rows: Vec<usize> = (0..points.nrows()).collect();
+        tree.root = tree.build(&mut rows);
+        tree
+    }
+
+    /// Builds the subtree over some rows and returns its root node.
+    fn build(&mut self, rows: &mut [usize]) -> Option<usize> {
+        let (&mut point, rest) = rows.split_first_mut()?;
+        let vantage = self.points.row(point);
+        let mut by_distance: Vec<(f32, usize)> = rest
+            .iter()
+            .map(|&row| (euclidean(vantage, self.points.row(row)), row))
+            .collect();
+        let middle = by_distance.len() / 2;
+        let radius = if by_distance.is_empty() {
+            0.0
+        } else {
+            let (_, median, _) =
+                by_distance.select_nth_unstable_by(middle, |a, b| a.0.total_cmp(&b.0));
+            median.0
+        };
+        for (slot, (_, row)) in rest.iter_mut().zip(by_distance) {
+            *slot = row;
+        }
+
+        let node = self.nodes.len();
+        self.nodes.push(VpNode {
+            point,
+            radius,
+            inside: None,
+            outside: None,
+        });
+        let (inside, outside) = rest.split_at_mut(middle);
+        self.nodes[node].inside = self.build(inside);
+        self.nodes[node].outside = self.build(outside);
+        Some(node)
+    }
+
+    /// Finds all points closer than `radius` to a query point.
+    ///
+    /// # Arguments
+    ///
+    /// * `query` - The query point.
+    /// * `radius` - The exclusive search radius.
+    pub fn within(&self, query: ArrayView1<f32>, radius: f32) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            let distance = euclidean(query, self.points.row(node.point));
+            if distance < radius {
+                found.push(node.point);
+            }
+            if let Some(inside) = node.inside {
+                if distance - radius <= node.radius {
+                    stack.push(inside);
+                }
+            }
+            if let Some(outside) = node.outside {
+                if distance + radius >= node.radius {
+                    stack.push(outside);
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Clusters weighted points with DBSCAN under the Euclidean distance.
+///
+/// A point stands for `weight` identical log lines, so deduplicated messages are clustered
+/// exactly like the lines they came from: a point is a core point when the total weight of the
+/// points closer than `epsilon`, itself included, is at least `min_points`. Neighbourhoods are
+/// found with a `VpTree` and each point is queued for expansion at most once, so memory stays
+/// linear in the number of points.
+///
+/// Returns the cluster of each point, numbered in order of discovery, or `None` for noise.
+///
+/// # Arguments
+///
+/// * `points` - The points, one per row.
+/// * `weights` - The weight of each point.
+/// * `epsilon` - The maximum distance between two points for one to be considered as in the neighborhood of the other.
+/// * `min_points` - The minimum total weight of a dense region (a cluster).
+pub fn dbscan(
+    points: &Array2<f32>,
+    weights: &[u64],
+    epsilon: f32,
+    min_points: usize,
+) -> Vec<Option<usize>> {
+    dbscan_with_queue_size(points, weights, epsilon, min_points).0
+}
+
+/// Runs `dbscan` and also returns the largest size its expansion queue reached.
+fn dbscan_with_queue_size(
+    points: &Array2<f32>,
+    weights: &[u64],
+    epsilon: f32,
+    min_points: usize,
+) -> (Vec<Option<usize>>, usize) {
+    let tree = VpTree::new(points);
+    let is_core = |neighbours: &[usize]| {
+        neighbours.iter().map(|&i| weights[i]).sum::<u64>() >= min_points as u64
+    };
+
+    let mut clusters: Vec<Option<usize>> = vec![None; points.nrows()];
+    let mut visited = vec![false; points.nrows()];
+    let mut next_cluster = 0;
+    let mut max_queue = 0;
+    for start in 0..points.nrows() {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let neighbours = tree.within(points.row(start), epsilon);
+        if !is_core(&neighbours) {
+            continue;
+        }
+        // Points are assigned to the cluster when they are queued, so each point enters the
+        // queue at most once. Points visited before as noise are border points of this cluster.
+        let mut queue = Vec::new();
+        clusters[start] = Some(next_cluster);
+        for i in neighbours {
+            if clusters[i].is_none() {
+                clusters[i] = Some(next_cluster);
+                queue.push(i);
+            }
+        }
+        while let Some(point) = queue.pop() {
+            if visited[point] {
+                continue;
+            }
+            visited[point] = true;
+            let neighbours = tree.within(points.row(point), epsilon);
+            if is_core(&neighbours) {
+                for i in neighbours {
+                    if clusters[i].is_none() {
+                        clusters[i] = Some(next_cluster);
+                        queue.push(i);
+                    }
+                }
+            }
+            max_queue = max_queue.max(queue.len());
+        }
+        next_cluster += 1;
+    }
+    debug!(
+        "DBSCAN over {} points found {} clusters.",
+        points.nrows(),
+        next_cluster
+    );
+    (clusters, max_queue)
+}
+
+/// Clusters weighted points with online leader clustering under the Euclidean distance.
+///
+/// The points are visited once, in order. Each one joins the cluster with the nearest running
+/// centroid if it is closer than `epsilon`, moving that centroid towards it, and starts a new
+/// cluster otherwise. Clusters standing for fewer than `min_points` lines are noise. Only the
+/// centroids are kept, so it needs no neighbourhood queries at all, but the result depends on
+/// the order of the points.
+///
+/// Returns the cluster of each point, numbered in order of creation, or `None` for noise.
+///
+/// # Arguments
+///
+/// * `points` - The points, one per row.
+/// * `weights` - The weight of each point.
+/// * `epsilon` - The maximum distance from a point to the centroid of its cluster.
+/// * `min_points` - The minimum total weight of a cluster.
+pub fn leader(
+    points: &Array2<f32>,
+    weights: &[u64],
+    epsilon: f32,
+    min_points: usize,
+) -> Vec<Option<usize>> {
+    let mut centroids: Vec<Array1<f32>> = Vec::new();
+    let mut totals: Vec<u64> = Vec::new();
+    let mut assignments = Vec::with_capacity(points.nrows());
+    for (point, &weight) in points.outer_iter().zip(weights) {
+        let nearest = centroids
+            .iter()
+            .map(|centroid| euclidean(point, centroid.view()))
+            .enumerate()
+            .filter(|&(_, distance)| distance < epsilon)
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        match nearest {
+            Some((cluster, _)) => {
+                totals[cluster] += weight;
+                let rate = weight as f32 / totals[cluster] as f32;
+                let update = (&point - &centroids[cluster]) * rate;
+                centroids[cluster] += &update;
+                assignments.push(cluster);
+            }
+            None => {
+                centroids.push(point.to_owned());
+                totals.push(weight);
+                assignments.push(centroids.len() - 1);
+            }
+        }
+    }
+
+    // Renumber the clusters that are large enough.
+    let mut numbers = vec![None; totals.len()];
+    let mut next_cluster = 0;
+    for (number, &total) in numbers.iter_mut().zip(&totals) {
+        if total >= min_points as u64 {
+            *number = Some(next_cluster);
+            next_cluster += 1;
+        }
+    }
+    debug!(
+        "Leader clustering over {} points found {} clusters, {} of them large enough.",
+        points.nrows(),
+        totals.len(),
+        next_cluster
+    );
+    assignments.into_iter().map(|c| numbers[c]).collect()
+}
+
+/// A merge of two subtrees in the single-linkage hierarchy built by `hdbscan`.
+struct Merge {
+    /// The merged subtrees: points below `n`, earlier merges `n + i` above.
+    children: [usize; 2],
+    /// The mutual reachability distance at which the subtrees merge.
+    distance: f32,
+}
+
+/// A cluster of the condensed hierarchy built by `hdbscan`.
+struct CondensedCluster {
+    parent: Option<usize>,
+    /// The density (inverse distance) at which the cluster splits off its parent.
+    birth: f64,
+    /// The total weighted density range over which points belong to the cluster.
+    stability: f64,
+    children: Vec<usize>,
+}
+
+/// Converts a distance into a density level, capped for identical points.
+fn lambda(distance: f32) -> f64 {
+    1.0 / (distance as f64).max(1e-9)
+}
+
+/// Clusters weighted points with HDBSCAN under the Euclidean distance.
+///
+/// Instead of a single epsilon, it considers every density level: the core distance of a point
+/// is the radius holding `min_points` lines around it, and points are linked by their mutual
+/// reachability distance, the largest of their distance and both core distances. Going down the
+/// minimum spanning tree of these links from the sparsest level, a cluster splits whenever both
+/// parts still hold `min_points` lines, and smaller parts fall out as noise. The clusters that
+/// persist over the widest range of densities (the most stable ones) are selected, so tight and
+/// loose templates can both be found in one run. The whole input is never selected as a single
+/// cluster.
+///
+/// It takes time quadratic in the number of points and linear memory.
+///
+/// Returns the cluster of each point, numbered in order of discovery, or `None` for noise.
+///
+/// # Arguments
+///
+/// * `points` - The points, one per row.
+/// * `weights` - The weight of each point.
+/// * `min_points` - The number of lines defining the core distance, and the minimum total weight
+///   of a cluster.
+pub fn hdbscan(points: &Array2<f32>, weights: &[u64], min_points: usize) -> Vec<Option<usize>> {
+    let count = points.nrows();
+    let min_points = min_points.max(1) as u64;
+    if count < 2 {
+        return vec![None; count];
+    }
+
+    // The core distance: the smallest radius around a point holding `min_points` lines.
+    let core: Vec<f32> = (0..count)
+        .map(|i| {
+            if weights[i] >= min_points {
+                return 0.0;
+            }
+            let mut others: Vec<(f32, u64)> = (0..count)
+                .filter(|&j| j != i)
+                .map(|j| (euclidean(points.row(i), points.row(j)), weights[j]))
+                .collect();
+            // Every point weighs at least 1, so the radius is among the nearest `min_points`.
+            let nearest = (min_points as usize - 1).min(others.len() - 1);
+            others.select_nth_unstable_by(nearest, |a, b| a.0.total_cmp(&b.0));
+            others.truncate(nearest + 1);
+            others.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+            let mut total = weights[i];
+            for (distance, weight) in &others {
+                total += weight;
+                if total >= min_points {
+                    return *distance;
+                }
+            }
+            others.last().map_or(f32::INFINITY, |o| o.0)
+        })
+        .collect();
+
+    // The minimum spanning tree of the mutual reachability distances (Prim's algorithm).
+    let mut in_tree = vec![false; count];
+    let mut best = vec![(f32::INFINITY, 0usize); count];
+    let mut edges: Vec<(f32, usize, usize)> = Vec::with_capacity(count - 1);
+    let mut current = 0;
+    in_tree[0] = true;
+    for _ in 1..count {
+        let mut next = None;
+        for j in 0..count {
+            if in_tree[j] {
+                continue;
+            }
+            let reachability = euclidean(points.row(current), points.row(j))
+                .max(core[current])
+                .max(core[j]);
+            if reachability < best[j].0 {
+                best[j] = (reachability, current);
+            }
+            if next.is_none_or(|n: usize| best[j].0 < best[n].0) {
+                next = Some(j);
+            }
+        }
+        let next = next.expect("a point outside the tree");
+        in_tree[next] = true;
+        edges.push((best[next].0, best[next].1, next));
+        current = next;
+    }
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    // The single-linkage hierarchy, merging subtrees from the densest link up.
+    let mut owner: Vec<usize> = (0..count).collect();
+    let mut subtree: Vec<usize> = (0..count).collect();
+    let mut sizes: Vec<u64> = weights.to_vec();
+    let mut merges: Vec<Merge> = Vec::with_capacity(count - 1);
+    let find = |owner: &mut Vec<usize>, mut i: usize| {
+        while owner[i] != i {
+            owner[i] = owner[owner[i]];
+            i = owner[i];
+        }
+        i
+    };
+    for (distance, a, b) in edges {
+        let (a, b) = (find(&mut owner, a), find(&mut owner, b));
+        merges.push(Merge {
+            children: [subtree[a], subtree[b]],
+            distance,
+        });
+        sizes.push(sizes[subtree[a]] + sizes[subtree[b]]);
+        owner[b] = a;
+        subtree[a] = count + merges.len() - 1;
+    }
+
+    // The condensed hierarchy: walking down from the root, a split only creates new clusters
+    // if both parts are large enough; otherwise the small part falls out as noise.
+    let mut clusters = vec![CondensedCluster {
+        parent: None,
+        birth: 0.0,
+        stability: 0.0,
+        children: Vec::new(),
+    }];
+    // The cluster each point last belonged to.
+    let mut fell_out_of = vec![0; count];
+    let leaves = |node: usize| {
+        let mut found = Vec::new();
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            if node < count {
+                found.push(node);
+            } else {
+                stack.extend(merges[node - count].children);
+            }
+        }
+        found
+    };
+    // Subtrees still to split, with their cluster and the density at which they were reached.
+    let mut stack = vec![(count + merges.len() - 1, 0, 0.0)];
+    while let Some((node, cluster, reached)) = stack.pop() {
+        if node < count {
+            // A single point splits into nothing and leaves the cluster where it was reached.
+            let condensed = &mut clusters[cluster];
+            condensed.stability += sizes[node] as f64 * (reached - condensed.birth);
+            fell_out_of[node] = cluster;
+            continue;
+        }
+        let merge = &merges[node - count];
+        let level = lambda(merge.distance);
+        let birth = clusters[cluster].birth;
+        let [left, right] = merge.children;
+        let large = |child: usize| sizes[child] >= min_points;
+        if large(left) && large(right) {
+            for child in [left, right] {
+                clusters[cluster].stability += sizes[child] as f64 * (level - birth);
+                clusters.push(CondensedCluster {
+                    parent: Some(cluster),
+                    birth: level,
+                    stability: 0.0,
+                    children: Vec::new(),
+                });
+                let new_cluster = clusters.len() - 1;
+                clusters[cluster].children.push(new_cluster);
+                stack.push((child, new_cluster, level));
+            }
+        } else {
+            for child in [left, right] {
+                if large(child) {
+                    stack.push((child, cluster, level));
+                } else {
+                    clusters[cluster].stability += sizes[child] as f64 * (level - birth);
+                    for point in leaves(child) {
+                        fell_out_of[point] = cluster;
+                    }
+                }
+            }
+        }
+    }
+
+    // Select the most stable clusters: a cluster replaces its descendants if it is at least as
+    // stable as the best selection among them. Children are always created after their parent.
+    let mut selected = vec![false; clusters.len()];
+    let mut value = vec![0.0; clusters.len()];
+    for cluster in (1..clusters.len()).rev() {
+        let below: f64 = clusters[cluster].children.iter().map(|&c| value[c]).sum();
+        if clusters[cluster].children.is_empty() || clusters[cluster].stability >= below {
+            selected[cluster] = true;
+            value[cluster] = clusters[cluster].stability;
+        } else {
+            value[cluster] = below;
+        }
+    }
+    // Keep only the topmost selected clusters, numbered in order of creation.
+    let mut numbers: Vec<Option<usize>> = vec![None; clusters.len()];
+    let mut next_cluster = 0;
+    for cluster in 1..clusters.len() {
+        let mut ancestor = clusters[cluster].parent;
+        let mut covered = false;
+        while let Some(a) = ancestor {
+            covered |= selected[a];
+            ancestor = clusters[a].parent;
+        }
+        if selected[cluster] && !covered {
+            numbers[cluster] = Some(next_cluster);
+            next_cluster += 1;
+        }
+    }
+    debug!(
+        "HDBSCAN over {} points found {} clusters.",
+        count, next_cluster
+    );
+
+    fell_out_of
+        .into_iter()
+        .map(|mut cluster| loop {
+            if let Some(number) = numbers[cluster] {
+                return Some(number);
+            }
+            cluster = clusters[cluster].parent?;
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_dbscan_matches_brute_force_neighbourhoods() {
+        let mut state: u32 = 7;
+        let points = Array2::from_shape_fn((300, 5), |(row, _)| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            // Three blobs around 0, 10 and 20, and scattered points.
+            let jitter = (state >> 8) as f32 / (1 << 24) as f32;
+            match row % 4 {
+                3 => 40.0 * jitter,
+                blob => 10.0 * blob as f32 + jitter,
+            }
+        });
+        let tree = VpTree::new(&points);
+        for query in [0, 3, 150, 299] {
+            let mut found = tree.within(points.row(query), 1.5);
+            found.sort_unstable();
+            let expected: Vec<usize> = (0..300)
+                .filter(|&i| euclidean(points.row(query), points.row(i)) < 1.5)
+                .collect();
+            assert_eq!(found, expected);
+        }
+
+        let weights = vec![1; 300];
+        let clusters = dbscan(&points, &weights, 1.5, 5);
+        for row in 0..3 {
+            assert_eq!(clusters[row], Some(row));
+            assert!((row..300).step_by(4).all(|i| clusters[i] == Some(row)));
+        }
+
+        // A point visited first as noise becomes a border point of a later cluster.
+        let chain = Array2::from_shape_vec((3, 1), vec![0.0, 0.9, 1.8]).unwrap();
+        assert_eq!(dbscan(&chain, &[1, 1, 1], 1.0, 3), [Some(0); 3]);
+
+        // A single point standing for enough lines is a cluster of its own.
+        let lonely = Array2::from_shape_vec((2, 1), vec![0.0, 100.0]).unwrap();
+        assert_eq!(dbscan(&lonely, &[5, 1], 1.0, 5), [Some(0), None]);
+    }
+
+    #[test]
+    fn test_dbscan_queues_each_point_at_most_once() {
+        let mut state: u32 = 11;
+        let points = Array2::from_shape_fn((500, 5), |_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1 << 24) as f32 * 0.01
+        });
+        let weights = vec![1; 500];
+        let (clusters, max_queue) = dbscan_with_queue_size(&points, &weights, 1.0, 5);
+        assert!(clusters.iter().all(|&c| c == Some(0)));
+        assert!(max_queue <= 500, "queue grew to {max_queue}");
+    }
+
+    #[test]
+    fn test_hdbscan_and_leader_find_clusters_of_different_density() {
+        // A tight blob, a loose blob 10 times wider, and two isolated points.
+        let mut state: u32 = 11;
+        let mut jitter = || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+        };
+        let mut rows = Vec::new();
+        for _ in 0..30 {
+            rows.extend([0.1 * jitter(), 0.1 * jitter()]);
+        }
+        for _ in 0..30 {
+            rows.extend([20.0 + jitter(), jitter()]);
+        }
+        rows.extend([-30.0, 40.0, 50.0, -50.0]);
+        let points = Array2::from_shape_vec((62, 2), rows).unwrap();
+        let weights = vec![1; 62];
+
+        let expected: Vec<Option<usize>> = (0..62)
+            .map(|i| match i {
+                0..30 => Some(0),
+                30..60 => Some(1),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(hdbscan(&points, &weights, 5), expected);
+        // An epsilon small enough for the tight blob breaks up the loose one.
+        assert!(dbscan(&points, &weights, 0.05, 5).iter().flatten().max() != Some(&1));
+        assert_eq!(leader(&points, &weights, 2.0, 5), expected);
+
+        // Weights count as lines: each blob needs to stand for 31 lines.
+        assert_eq!(hdbscan(&points, &weights, 31), [None; 62]);
+        assert_eq!(hdbscan(&points, &vec![2; 62], 31), expected);
+    }
+}
@@ -1,42 +1,42 @@
-use crate::embedding::EmbeddingModel;
-use crate::preprocessing::LogPreprocessor;
+use crate::checkpoint::CheckpointStore;
+use crate::clustering::{dbscan, hdbscan, leader, ClusteringAlgorithm};
+use crate::embedding::Embedder;
+use crate::index::HnswIndex;
+use crate::input::{open_input, FollowReader, InputPosition, Poll, STDIN};
+use crate::model::{
+    cluster_radius, Cluster, DistanceMetric, LogModel, MatchThreshold, ModelIdentity, Nearest,
+    MAX_EXEMPLARS,
+};
+use crate::output::{MatchRecord, OutputFormat, Verdict};
+use crate::patterns::{BUILTIN_PATTERNS, BUILTIN_PATTERNS_VERSION};
+use crate::preprocessing::{LogPreprocessor, Trace};
+use crate::sampling::Reservoir;
+use crate::template::extract_template;
+use crate::timestamp::TimestampExtractor;
+use crate::tuning::{find_knee, k_distances, TuneCandidate, EPSILON_FACTORS};
 use anyhow::{Error as E, Result};
-use chrono::{DateTime, Local};
-use dbscan::{Classification, Model};
-use ndarray::{concatenate, s, Array1, Array2, Axis};
-use ndarray_stats::DeviationExt;
-use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use ndarray::{s, Array1, Array2, ArrayView1, Axis};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Write};
-
-/// Saves the centroids to a file in JSON format.
-///
-/// # Arguments
-///
-/// * `centroids` - A 2D array of centroids to save.
-/// * `path` - The path to the file where the centroids will be saved.
-fn save_centroids(centroids: &Array2<f32>, path: &str) -> Result<()> {
-    let file = File::create(path)?;
-    let mut writer = BufWriter::new(file);
-    serde_json::to_writer(&mut writer, centroids)?;
-    writer.flush()?;
-    Ok(())
-}
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufRead, BufWriter, IsTerminal, Stdout, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
 /// Processes a log file line by line, applying a preprocessor and a processor function.
 ///
 /// # Arguments
 ///
-/// * `path` - The path to the log file.
+/// * `path` - The path to the log file, or `-` for standard input.
 /// * `preprocessor` - The `LogPreprocessor` to apply to each line.
 /// * `processor` - A closure that takes the original and preprocessed line and performs an action.
 fn process_log_file<F>(path: &str, preprocessor: &LogPreprocessor, mut processor: F) -> Result<()>
 where
     F: FnMut(String, String) -> Result<()>,
 {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    for line in reader.lines() {
+    for line in open_input(path)?.lines() {
         let line = line?;
         let preprocessed = preprocessor.preprocess(&line);
         processor(line, preprocessed)?;
@@ -44,365 +44,1707 @@ where
     Ok(())
 }
 
-/// Trains the model on a log file to create initial centroids.
+/// Generates the embedding of a single log message.
+///
+/// # Arguments
+///
+/// * `model` - The embedder.
+/// * `message` - The preprocessed log message.
+fn embed_one(model: &mut impl Embedder, message: &str) -> Result<Array1<f32>> {
+    Ok(model.embed(&[message])?.row(0).to_owned())
+}
+
+/// Clusters the rows of an embedding matrix.
 ///
-/// This function reads a log file in batches to avoid loading the entire file into memory.
-/// It generates embeddings for each batch, concatenates them, and then uses DBSCAN
-/// clustering to find patterns and create centroids.
+/// Returns the cluster of each row, or `None` for noise.
 ///
 /// # Arguments
 ///
-/// * `input_file` - The path to the log file to train on.
-/// * `output_file` - The path to save the centroids to.
-/// * `epsilon` - The maximum distance between two points for one to be considered as in the neighborhood of the other.
-/// * `min_points` - The minimum number of points required to form a dense region (a cluster).
-/// * `preprocessor` - The `LogPreprocessor` to apply to each log message.
-/// * `verbose` - A boolean flag to enable detailed logging.
-use log::{debug, info, warn};
-pub fn train(
-    input_file: &str,
-    output_file: &str,
+/// * `embeddings` - The embeddings, one row per distinct log message.
+/// * `weights` - The number of log lines each row stands for.
+/// * `algorithm` - The clustering algorithm.
+/// * `epsilon` - The maximum distance between two points for one to be considered as in the neighborhood of the other (not used by HDBSCAN).
+/// * `min_points` - The minimum number of log lines required to form a dense region (a cluster).
+/// * `metric` - The distance metric `epsilon` is measured in.
+fn run_clustering(
+    embeddings: &Array2<f32>,
+    weights: &[u64],
+    algorithm: ClusteringAlgorithm,
     epsilon: f32,
     min_points: usize,
-    preprocessor: &LogPreprocessor,
-    _verbose: bool,
-) -> Result<()> {
-    let mut model = EmbeddingModel::load()?;
-
-    const BATCH_SIZE: usize = 1024;
-    let mut embedding_batches = Vec::new();
-
-    info!("Reading and parsing log file in batches: {}", input_file);
-    let file = File::open(input_file)?;
-    let reader = BufReader::new(file);
-    let mut lines_iterator = reader.lines();
-
-    loop {
-        let mut batch_lines = Vec::with_capacity(BATCH_SIZE);
-        for _ in 0..BATCH_SIZE {
-            if let Some(line_result) = lines_iterator.next() {
-                batch_lines.push(line_result?);
-            } else {
-                break;
+    metric: DistanceMetric,
+) -> Vec<Option<usize>> {
+    match algorithm {
+        ClusteringAlgorithm::Hdbscan => info!(
+            "Running HDBSCAN clustering with min_points={} ({} distance)...",
+            min_points, metric
+        ),
+        _ => info!(
+            "Running {} clustering with epsilon={} and min_points={} ({} distance)...",
+            algorithm.to_string().to_uppercase(),
+            epsilon,
+            min_points,
+            metric
+        ),
+    }
+    let normalized;
+    let (points, epsilon) = match metric {
+        DistanceMetric::Euclidean => (embeddings, epsilon),
+        // The clustering measures Euclidean distances. Between unit vectors, the Euclidean
+        // distance is sqrt(2 * cosine distance), so the neighborhoods are the same.
+        DistanceMetric::Cosine => {
+            let mut points = embeddings.clone();
+            for row in points.outer_iter_mut() {
+                metric.normalize(row);
             }
+            normalized = points;
+            (&normalized, (2.0 * epsilon).sqrt())
         }
+    };
+    match algorithm {
+        ClusteringAlgorithm::Dbscan => dbscan(points, weights, epsilon, min_points),
+        ClusteringAlgorithm::Hdbscan => hdbscan(points, weights, min_points),
+        ClusteringAlgorithm::Leader => leader(points, weights, epsilon, min_points),
+    }
+}
+
+/// A distinct preprocessed message read by `train` or `retrain`.
+struct NewMessage {
+    text: String,
+    count: u64,
+    exemplars: Vec<String>,
+}
 
-        if batch_lines.is_empty() {
-            break;
+/// Reads the distinct preprocessed messages of a log file, with the number of lines each one
+/// stands for and a few exemplar lines.
+///
+/// Returns the messages and the number of lines read.
+///
+/// # Arguments
+///
+/// * `input_file` - The path to the log file, or `-` for standard input.
+/// * `preprocessor` - The `LogPreprocessor` to apply to each line.
+/// * `sample_size` - Keep only a uniform random sample of this many lines, or `None` to keep
+///   all lines.
+fn read_messages(
+    input_file: &str,
+    preprocessor: &LogPreprocessor,
+    sample_size: Option<usize>,
+) -> Result<(Vec<NewMessage>, u64)> {
+    let mut messages: Vec<NewMessage> = Vec::new();
+    let mut message_index: HashMap<String, usize> = HashMap::new();
+    let mut add_line = |original_line: String, preprocessed_message: String| {
+        let index = *message_index
+            .entry(preprocessed_message.clone())
+            .or_insert_with(|| {
+                messages.push(NewMessage {
+                    text: preprocessed_message,
+                    count: 0,
+                    exemplars: Vec::new(),
+                });
+                messages.len() - 1
+            });
+        let message = &mut messages[index];
+        message.count += 1;
+        if message.exemplars.len() < MAX_EXEMPLARS && !message.exemplars.contains(&original_line) {
+            message.exemplars.push(original_line);
         }
+    };
 
-        let batch_preprocessed: Vec<String> = batch_lines
-            .iter()
-            .map(|line| preprocessor.preprocess(line))
-            .collect();
-        let batch_str: Vec<&str> = batch_preprocessed.iter().map(|s| s.as_str()).collect();
+    let total_lines = match sample_size {
+        None => {
+            let mut total_lines = 0;
+            process_log_file(
+                input_file,
+                preprocessor,
+                |original_line, preprocessed_message| {
+                    total_lines += 1;
+                    add_line(original_line, preprocessed_message);
+                    Ok(())
+                },
+            )?;
+            total_lines
+        }
+        Some(sample_size) => {
+            let mut sample = Reservoir::new(sample_size, 0);
+            process_log_file(
+                input_file,
+                preprocessor,
+                |original_line, preprocessed_message| {
+                    sample.offer((original_line, preprocessed_message));
+                    Ok(())
+                },
+            )?;
+            let total_lines = sample.seen();
+            for (original_line, preprocessed_message) in sample.into_items() {
+                add_line(original_line, preprocessed_message);
+            }
+            total_lines
+        }
+    };
+    Ok((messages, total_lines))
+}
 
-        info!(
+/// Embeds messages in batches, so that the model never sees more than one batch at a time.
+///
+/// # Arguments
+///
+/// * `model` - The embedder.
+/// * `messages` - The messages to embed.
+fn embed_messages(model: &mut impl Embedder, messages: &[NewMessage]) -> Result<Array2<f32>> {
+    let texts: Vec<&str> = messages.iter().map(|m| m.text.as_str()).collect();
+    embed_texts(model, &texts)
+}
+
+/// Embeds texts in batches, so that the model never sees more than one batch at a time.
+///
+/// # Arguments
+///
+/// * `model` - The embedder.
+/// * `texts` - The texts to embed.
+fn embed_texts(model: &mut impl Embedder, texts: &[&str]) -> Result<Array2<f32>> {
+    const BATCH_SIZE: usize = 1024;
+    let mut values = Vec::new();
+    let mut dimensions = 0;
+    for batch in texts.chunks(BATCH_SIZE) {
+        debug!(
             "Generating embeddings for batch of {} log messages...",
-            batch_lines.len()
+            batch.len()
         );
-        let embeddings_tensor = model.embed(&batch_str)?;
-        let (num_sentences, num_dims) = embeddings_tensor.dims2()?;
-        let embeddings_vec: Vec<f32> = embeddings_tensor.flatten_all()?.to_vec1()?;
-        let embeddings_array = Array2::from_shape_vec((num_sentences, num_dims), embeddings_vec)?;
-        embedding_batches.push(embeddings_array);
+        let embeddings = model.embed(batch)?;
+        dimensions = embeddings.ncols();
+        values.extend(embeddings.iter());
     }
+    Ok(Array2::from_shape_vec((texts.len(), dimensions), values)?)
+}
 
-    if embedding_batches.is_empty() {
-        warn!("No log messages found in input file.");
-        return Ok(());
+/// Adds a cluster for a group of messages, with the centroid weighted by the number of lines
+/// of each message, the radius taken from the distances of its lines to the centroid and the
+/// template derived from the messages.
+///
+/// # Arguments
+///
+/// * `log_model` - The model to add the cluster to.
+/// * `embeddings` - The embeddings of all messages.
+/// * `messages` - All messages.
+/// * `members` - The indices of the messages in the group.
+/// * `seen` - The time the messages were seen.
+fn add_message_cluster<'a>(
+    log_model: &'a mut LogModel,
+    embeddings: &Array2<f32>,
+    messages: &[NewMessage],
+    members: &[usize],
+    seen: DateTime<Utc>,
+) -> Result<&'a mut Cluster> {
+    let mut sum = Array1::<f32>::zeros(embeddings.ncols());
+    let mut hits = 0;
+    for &i in members {
+        sum.scaled_add(messages[i].count as f32, &embeddings.row(i));
+        hits += messages[i].count;
     }
+    log_model.add_cluster((sum / hits as f32).view())?;
+    let centroid = log_model.centroids.row(log_model.centroids.nrows() - 1);
+    let distances = members
+        .iter()
+        .map(|&i| {
+            let distance = log_model
+                .metadata
+                .metric
+                .distance(centroid, embeddings.row(i))?;
+            Ok((distance, messages[i].count))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let cluster = log_model
+        .clusters
+        .last_mut()
+        .expect("cluster was just added");
+    cluster.radius = cluster_radius(&distances);
+    cluster.template = extract_template(members.iter().map(|&i| messages[i].text.as_str()));
+    for &i in members {
+        for line in &messages[i].exemplars {
+            cluster.record_hit(line, seen);
+        }
+    }
+    cluster.hits = hits;
+    Ok(cluster)
+}
 
-    let embeddings_array = concatenate(
-        Axis(0),
-        &embedding_batches
-            .iter()
-            .map(|v| v.view())
-            .collect::<Vec<_>>(),
-    )
-    .map_err(|e| E::msg(e.to_string()))?;
+/// Finds the centroids closest to an embedding, using the nearest-neighbour index if there is
+/// one and comparing with every centroid otherwise.
+fn find_nearest(
+    log_model: &LogModel,
+    index: Option<&HnswIndex>,
+    embedding: ArrayView1<f32>,
+) -> Result<Option<Nearest>> {
+    match index {
+        Some(index) => index.nearest(&log_model.centroids, embedding),
+        None => log_model.nearest(embedding),
+    }
+}
 
-    let (_num_sentences, num_dims) = embeddings_array.dim();
+/// Tuning parameters for `train`.
+#[derive(Debug, Clone, Copy)]
+pub struct TrainOptions {
+    /// The maximum distance between two points for one to be considered as in the neighborhood of the other.
+    pub epsilon: f32,
+    /// The minimum number of points required to form a dense region (a cluster).
+    pub min_points: usize,
+    /// The distance metric for clustering, recorded in the model for matching.
+    pub metric: DistanceMetric,
+    /// Train on a uniform random sample of this many lines, or `None` to use all lines.
+    pub sample_size: Option<usize>,
+    /// The clustering algorithm.
+    pub algorithm: ClusteringAlgorithm,
+}
+
+/// Trains the model on a log file to create initial centroids.
+///
+/// Lines are deduplicated by their preprocessed text before embedding, so memory and time grow
+/// with the number of distinct messages rather than lines. The messages are then clustered with
+/// DBSCAN, each one weighted by its number of lines, which gives the same clusters as
+/// clustering every line. A sample size bounds the memory for inputs with very many distinct
+/// messages.
+///
+/// # Arguments
+///
+/// * `input_file` - The path to the log file to train on, or `-` for standard input.
+/// * `output_file` - The path to save the centroids to.
+/// * `options` - The `TrainOptions` controlling sampling and clustering.
+/// * `preprocessor` - The `LogPreprocessor` to apply to each log message.
+/// * `model` - The embedder turning log messages into vectors.
+/// * `verbose` - A boolean flag to enable detailed logging.
+pub fn train(
+    input_file: &str,
+    output_file: &str,
+    options: &TrainOptions,
+    preprocessor: &LogPreprocessor,
+    model: &mut impl Embedder,
+    _verbose: bool,
+) -> Result<()> {
+    let TrainOptions {
+        epsilon,
+        min_points,
+        metric,
+        sample_size,
+        algorithm,
+    } = *options;
+
+    info!("Reading and parsing log file: {}", input_file);
+    let (messages, total_lines) = read_messages(input_file, preprocessor, sample_size)?;
+    if messages.is_empty() {
+        warn!("No log messages found in input file.");
+        return Ok(());
+    }
+    let sampled_lines: u64 = messages.iter().map(|m| m.count).sum();
+    if sampled_lines < total_lines {
+        info!("Sampled {sampled_lines} of {total_lines} lines.");
+    }
 
     info!(
-        "Running DBSCAN clustering with epsilon={} and min_points={}...",
-        epsilon, min_points
+        "Generating embeddings for {} distinct messages out of {} lines...",
+        messages.len(),
+        sampled_lines
     );
-    let dbscan = Model::new(epsilon as f64, min_points);
-    let clusters = dbscan.run(
-        &embeddings_array
-            .outer_iter()
-            .map(|row| row.to_vec())
-            .collect::<Vec<_>>(),
+    let embeddings = embed_messages(model, &messages)?;
+    let weights: Vec<u64> = messages.iter().map(|m| m.count).collect();
+    let clusters = run_clustering(
+        &embeddings,
+        &weights,
+        algorithm,
+        epsilon,
+        min_points,
+        metric,
     );
 
     debug!("--- Cluster Assignments ---");
-    let file = File::open(input_file)?;
-    let reader = BufReader::new(file);
-    for (i, line_result) in reader.lines().enumerate() {
-        if i >= clusters.len() {
-            break;
-        }
-        let line = line_result?;
-        match clusters[i] {
-            Classification::Noise => {
-                debug!("Noise -> {line}");
+    let mut members: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    let mut noise_points = 0;
+    for (i, (message, cluster)) in messages.iter().zip(&clusters).enumerate() {
+        match *cluster {
+            None => {
+                debug!("Noise ({} lines) -> {}", message.count, message.text);
+                noise_points += message.count;
             }
-            Classification::Core(id) | Classification::Edge(id) => {
-                debug!("Cluster {id} -> {line}");
+            Some(id) => {
+                debug!("Cluster {id} ({} lines) -> {}", message.count, message.text);
+                members.entry(id).or_default().push(i);
             }
         }
     }
     debug!("-------------------------");
 
-    let mut cluster_map: HashMap<usize, Vec<Array1<f32>>> = HashMap::new();
-    let mut noise_points = 0;
-
-    for (i, &cluster_id) in clusters.iter().enumerate() {
-        match cluster_id {
-            Classification::Noise => noise_points += 1,
-            Classification::Core(id) | Classification::Edge(id) => {
-                cluster_map
-                    .entry(id)
-                    .or_default()
-                    .push(embeddings_array.row(i).to_owned());
-            }
-        }
+    if members.is_empty() {
+        return Err(E::msg(format!(
+            "{} did not find any clusters. Try adjusting epsilon or min_points.",
+            algorithm.to_string().to_uppercase()
+        )));
     }
 
-    if cluster_map.is_empty() {
-        return Err(E::msg(
-            "DBSCAN did not find any clusters. Try adjusting epsilon or min_points.",
-        ));
+    let identity = ModelIdentity::new(model, preprocessor);
+    let mut log_model = LogModel::new(embeddings.ncols(), &identity, epsilon, min_points, metric);
+    log_model.metadata.algorithm = algorithm;
+    if algorithm == ClusteringAlgorithm::Hdbscan {
+        log_model.metadata.epsilon = None;
     }
-
-    let mut centroids_list = Vec::new();
-    for (_id, points) in cluster_map {
-        let mut sum = Array1::zeros(num_dims);
-        for p in &points {
-            sum += p;
-        }
-        let mean = sum / points.len() as f32;
-        centroids_list.push(mean.insert_axis(Axis(0)));
+    let now = Utc::now();
+    for rows in members.values() {
+        add_message_cluster(&mut log_model, &embeddings, &messages, rows, now)?;
     }
 
-    let centroids = concatenate(
-        Axis(0),
-        &centroids_list.iter().map(|v| v.view()).collect::<Vec<_>>(),
-    )
-    .map_err(|e| E::msg(e.to_string()))?;
-
-    save_centroids(&centroids, output_file)?;
+    let centroids_count = log_model.clusters.len();
+    log_model.save(output_file)?;
+    model.flush()?;
 
     info!(
-        "DBSCAN found {} clusters and {} noise points.",
-        centroids.nrows(),
+        "{} found {} clusters and {} noise points.",
+        algorithm.to_string().to_uppercase(),
+        centroids_count,
         noise_points
     );
     info!(
         "Successfully saved {} centroids to {}",
-        centroids.nrows(),
-        output_file
+        centroids_count, output_file
     );
 
     Ok(())
 }
 
+/// Settings for following a growing input in `ingest`.
+#[derive(Debug, Clone)]
+pub struct FollowOptions {
+    /// How long to wait for new data before flushing output and checking for rotation.
+    pub poll_interval: Duration,
+    /// How often to save the updated centroids.
+    pub checkpoint_interval: Duration,
+}
+
+/// Tuning parameters for `ingest`.
+pub struct IngestOptions {
+    /// The distance threshold for matching a cluster.
+    pub threshold: MatchThreshold,
+    /// The learning rate for updating centroids and cluster radii on a match.
+    pub learning_rate: f64,
+    /// Continue with a warning if the model was built with a different embedding model or patterns.
+    pub allow_mismatch: bool,
+    /// The `TimestampExtractor` used to find the timestamp of each log message.
+    pub timestamps: TimestampExtractor,
+    /// Keep reading the input as it grows instead of stopping at its end.
+    pub follow: Option<FollowOptions>,
+    /// The file recording how far each input has been ingested, or `None` to always read
+    /// inputs from the beginning.
+    pub checkpoint_file: Option<String>,
+    /// Ignore any saved checkpoint and read the input from the beginning.
+    pub from_start: bool,
+    /// Write a result record for every line to standard output in this format.
+    pub output_format: Option<OutputFormat>,
+    /// The number of lines to embed together in one forward pass of the model.
+    pub batch_size: usize,
+    /// The nearest-neighbour index file to search and keep up to date, or `None` to compare
+    /// every message with all centroids.
+    pub index_file: Option<String>,
+}
+
+/// A log line read by `ingest` and waiting for its batch to be embedded.
+struct PendingLine {
+    original_line: String,
+    preprocessed_message: String,
+    params: Vec<(String, String)>,
+    parsed_timestamp: Option<DateTime<Utc>>,
+}
+
+/// The state of an ingestion run.
+struct Ingester<'a, M: Embedder> {
+    model: &'a mut M,
+    identity: ModelIdentity,
+    log_model: LogModel,
+    index: Option<HnswIndex>,
+    preprocessor: &'a LogPreprocessor,
+    options: IngestOptions,
+    checkpoints: CheckpointStore,
+    unmatched_writer: BufWriter<File>,
+    output: BufWriter<Stdout>,
+    pending: Vec<PendingLine>,
+    /// The match result of every message seen in this run. Repeats of a message reuse it
+    /// without being embedded again, so they report the cluster, distances and verdict of the
+    /// first occurrence, even though the centroid and radius have moved since.
+    seen_messages: HashMap<String, (Option<Nearest>, Verdict)>,
+    matched_count: usize,
+    total_count: usize,
+}
+
+impl<M: Embedder> Ingester<'_, M> {
+    /// Queues a log line for matching, and matches the queued lines once a full batch has
+    /// been collected.
+    fn process_line(&mut self, original_line: String) -> Result<()> {
+        let (preprocessed_message, params) =
+            self.preprocessor.preprocess_with_params(&original_line);
+        let parsed_timestamp = self.options.timestamps.extract(&original_line);
+        self.pending.push(PendingLine {
+            original_line,
+            preprocessed_message,
+            params,
+            parsed_timestamp,
+        });
+        if self.pending.len() >= self.options.batch_size {
+            self.process_batch()?;
+        }
+        Ok(())
+    }
+
+    /// Embeds the new messages among the queued lines in one forward pass, then matches the
+    /// lines against the centroids in their original order, updating the matched centroid or
+    /// writing the line to the unmatched file.
+    ///
+    /// The results are the same as when matching the lines one by one.
+    fn process_batch(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut new_messages: Vec<&str> = Vec::new();
+        let mut queued: HashSet<&str> = HashSet::new();
+        for line in &self.pending {
+            let message = line.preprocessed_message.as_str();
+            if !self.seen_messages.contains_key(message) && queued.insert(message) {
+                new_messages.push(message);
+            }
+        }
+        let mut embeddings: HashMap<String, Array1<f32>> = HashMap::new();
+        if !new_messages.is_empty() {
+            let rows = self.model.embed(&new_messages)?;
+            for (message, row) in new_messages.iter().zip(rows.outer_iter()) {
+                embeddings.insert(message.to_string(), row.to_owned());
+            }
+        }
+
+        for line in std::mem::take(&mut self.pending) {
+            let PendingLine {
+                original_line,
+                preprocessed_message,
+                params,
+                parsed_timestamp,
+            } = line;
+            let log_timestamp = parsed_timestamp.unwrap_or_else(|| {
+                debug!("No timestamp found, assuming current time: {original_line}");
+                Utc::now()
+            });
+
+            let (nearest, verdict) = match self.seen_messages.get(&preprocessed_message) {
+                Some(&(nearest, verdict)) => {
+                    if let (Some(nearest), Verdict::Matched) = (nearest, verdict) {
+                        let cluster = &mut self.log_model.clusters[nearest.index];
+                        cluster.record_hit(&original_line, log_timestamp);
+                        cluster.record_params(&params);
+                    }
+                    (nearest, verdict)
+                }
+                None => {
+                    let embedding = embeddings.remove(&preprocessed_message).ok_or_else(|| {
+                        E::msg(format!("No embedding for message: {preprocessed_message}"))
+                    })?;
+                    let result = self.match_new_message(
+                        &original_line,
+                        &preprocessed_message,
+                        &params,
+                        embedding,
+                        log_timestamp,
+                    )?;
+                    self.seen_messages
+                        .insert(preprocessed_message.clone(), result);
+                    result
+                }
+            };
+
+            if let Some(format) = self.options.output_format {
+                MatchRecord::new(
+                    &original_line,
+                    &preprocessed_message,
+                    &params,
+                    parsed_timestamp,
+                    nearest.as_ref(),
+                    &self.log_model,
+                    verdict,
+                )
+                .write(format, &mut self.output)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Matches a log message not seen before in this run, moving the matched centroid towards
+    /// it or writing it to the unmatched file.
+    fn match_new_message(
+        &mut self,
+        original_line: &str,
+        preprocessed_message: &str,
+        params: &[(String, String)],
+        message_embedding: Array1<f32>,
+        log_timestamp: DateTime<Utc>,
+    ) -> Result<(Option<Nearest>, Verdict)> {
+        self.total_count += 1;
+
+        let nearest = find_nearest(
+            &self.log_model,
+            self.index.as_ref(),
+            message_embedding.view(),
+        )?;
+        match nearest {
+            Some(Nearest {
+                index: closest_cluster_index,
+                distance: min_dist,
+                ..
+            }) if min_dist
+                < self
+                    .options
+                    .threshold
+                    .for_cluster(&self.log_model.clusters[closest_cluster_index]) =>
+            {
+                self.matched_count += 1;
+                let cluster = &mut self.log_model.clusters[closest_cluster_index];
+                debug!(
+                    "Match Cluster {} (distance: {:.4}) -> {preprocessed_message}",
+                    cluster.display_name(),
+                    min_dist
+                );
+                cluster.record_hit(original_line, log_timestamp);
+                cluster.record_params(params);
+                cluster.update_radius(min_dist, self.options.learning_rate);
+                let mut matched_centroid = self
+                    .log_model
+                    .centroids
+                    .slice_mut(s![closest_cluster_index, ..]);
+                let update =
+                    &(&message_embedding - &matched_centroid) * self.options.learning_rate as f32;
+                matched_centroid += &update;
+                self.log_model.metadata.metric.normalize(matched_centroid);
+                if let Some(index) = &mut self.index {
+                    index.update(closest_cluster_index, &self.log_model.centroids)?;
+                }
+                Ok((nearest, Verdict::Matched))
+            }
+            _ => {
+                debug!(
+                    "No match (distance: {:.4}) -> {preprocessed_message}",
+                    nearest.map_or(f64::INFINITY, |n| n.distance)
+                );
+                writeln!(self.unmatched_writer, "{original_line}")?;
+                Ok((nearest, Verdict::Unmatched))
+            }
+        }
+    }
+
+    /// Matches the queued lines and flushes the unmatched logs and any per-line results
+    /// written so far.
+    fn flush(&mut self) -> Result<()> {
+        self.process_batch()?;
+        self.unmatched_writer.flush()?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    /// Matches the queued lines, flushes the unmatched logs, saves the updated centroids and
+    /// records how far the input has been read.
+    ///
+    /// The centroids are saved before the input position, so an interruption in between causes
+    /// lines to be ingested twice rather than skipped.
+    fn checkpoint(
+        &mut self,
+        centroids_file: &str,
+        input_file: &str,
+        position: Option<InputPosition>,
+    ) -> Result<()> {
+        self.flush()?;
+        self.log_model.touch(&self.identity);
+        self.log_model.save(centroids_file)?;
+        if let (Some(index), Some(index_file)) = (&mut self.index, &self.options.index_file) {
+            index.save(index_file, &self.log_model.centroids)?;
+        }
+        self.model.flush()?;
+        if let (Some(checkpoint_file), Some(position)) = (&self.options.checkpoint_file, position) {
+            self.checkpoints.record(input_file, position);
+            self.checkpoints.save(checkpoint_file)?;
+        }
+        Ok(())
+    }
+}
+
 /// Ingests a file of new logs, updating centroids for matches and logging non-matches.
-/// It skips logs older than the centroids file and avoids reprocessing duplicate messages.
+/// It resumes each input file where the previous run stopped and avoids reprocessing duplicate
+/// messages.
+///
+/// When following, the input is read as it grows until standard input is closed or the process
+/// is stopped. Unmatched logs are flushed whenever the input is idle, and the centroids are saved
+/// at every checkpoint interval.
 ///
 /// # Arguments
 ///
-/// * `input_file` - The path to the file with new log messages.
+/// * `input_file` - The path to the file with new log messages, or `-` for standard input.
 /// * `centroids_file` - The path to the centroids file.
 /// * `unmatched_file` - The path for saving unmatched logs.
-/// * `threshold` - The distance threshold for matching a cluster.
-/// * `learning_rate` - The learning rate for updating centroids on a match.
+/// * `options` - The `IngestOptions` controlling matching and input handling.
 /// * `preprocessor` - The `LogPreprocessor` to apply to each log message.
+/// * `model` - The embedder turning log messages into vectors.
 /// * `_verbose` - A boolean flag to enable detailed logging (handled by the logger).
 pub fn ingest(
     input_file: &str,
     centroids_file: &str,
     unmatched_file: &str,
-    threshold: f64,
-    learning_rate: f64,
+    options: IngestOptions,
     preprocessor: &LogPreprocessor,
+    model: &mut impl Embedder,
     _verbose: bool,
 ) -> Result<()> {
-    let mut model = EmbeddingModel::load()?;
+    if options.batch_size == 0 {
+        return Err(E::msg("The batch size must be at least 1"));
+    }
+    let identity = ModelIdentity::new(model, preprocessor);
 
     info!("Loading centroids from {}...", centroids_file);
-    let file = File::open(centroids_file)?;
-    let mut centroids: Array2<f32> = serde_json::from_reader(file)?;
+    let log_model = LogModel::load(centroids_file)?;
+    log_model.check_compatible(&identity, options.allow_mismatch)?;
+    let index = match &options.index_file {
+        Some(index_file) => Some(HnswIndex::open(index_file, &log_model)?),
+        None => None,
+    };
 
-    let metadata = std::fs::metadata(centroids_file)?;
-    let last_modified: DateTime<Local> = metadata.modified()?.into();
+    let checkpoints = match &options.checkpoint_file {
+        Some(checkpoint_file) => CheckpointStore::load(checkpoint_file)?,
+        None => CheckpointStore::default(),
+    };
+    let resume_position =
+        if options.checkpoint_file.is_some() && !options.from_start && input_file != STDIN {
+            checkpoints.resume_position(input_file)?
+        } else {
+            None
+        };
 
     info!("Reading and parsing new log file: {}", input_file);
-    let mut unmatched_writer = BufWriter::new(
+    let unmatched_writer = BufWriter::new(
         OpenOptions::new()
             .create(true)
             .append(true)
             .open(unmatched_file)?,
     );
-    let mut matched_count = 0;
-    let mut total_count = 0;
-    let mut seen_messages = HashSet::new();
-
-    process_log_file(
-        input_file,
+    let follow = options.follow.clone();
+    let mut ingester = Ingester {
+        model,
+        identity,
+        log_model,
+        index,
         preprocessor,
-        |original_line, preprocessed_message| {
-            let log_timestamp_str = format!(
-                "{} {} +0100",
-                &original_line[..15],
-                Local::now().format("%Y")
-            );
-            let log_timestamp =
-                DateTime::parse_from_str(&log_timestamp_str, "%b %e %H:%M:%S %Y %z")
-                    .map(|parsed_time| parsed_time.with_timezone(&Local))
-                    .unwrap_or(Local::now());
-
-            if log_timestamp < last_modified {
-                debug!("Skipped {log_timestamp} < {last_modified}: {original_line}");
-                return Ok(());
-            }
+        options,
+        checkpoints,
+        unmatched_writer,
+        output: BufWriter::new(std::io::stdout()),
+        pending: Vec::new(),
+        seen_messages: HashMap::new(),
+        matched_count: 0,
+        total_count: 0,
+    };
 
-            if !seen_messages.insert(preprocessed_message.clone()) {
-                return Ok(());
+    let mut position = None;
+    if input_file == STDIN && follow.is_none() {
+        for line in open_input(input_file)?.lines() {
+            ingester.process_line(line?)?;
+        }
+    } else {
+        let poll_interval = follow.as_ref().map_or(Duration::ZERO, |f| f.poll_interval);
+        let mut reader = match &resume_position {
+            Some(resume_position) => {
+                FollowReader::resume(input_file, resume_position, poll_interval)?
             }
-
-            total_count += 1;
-            let message_embedding_tensor = model.embed(&[&preprocessed_message])?;
-            let message_vec: Vec<f32> = message_embedding_tensor.flatten_all()?.to_vec1()?;
-            let message_array = Array2::from_shape_vec((1, message_vec.len()), message_vec)?;
-            let message_embedding = message_array.row(0);
-
-            let mut min_dist = f64::INFINITY;
-            let mut closest_cluster_index = 0;
-
-            for (i, centroid) in centroids.axis_iter(Axis(0)).enumerate() {
-                let dist = centroid.l2_dist(&message_embedding)?;
-                if dist < min_dist {
-                    min_dist = dist;
-                    closest_cluster_index = i;
+            None => FollowReader::open(input_file, poll_interval)?,
+        };
+        if follow.is_some() {
+            info!("Following {input_file} for new log messages...");
+        }
+        let mut last_checkpoint = Instant::now();
+        loop {
+            match reader.poll_line()? {
+                Poll::Line(line) => ingester.process_line(line)?,
+                Poll::Idle if follow.is_none() => {
+                    if let Some(line) = reader.take_partial() {
+                        ingester.process_line(line)?;
+                    }
+                    break;
                 }
+                Poll::Idle => ingester.flush()?,
+                Poll::Closed => break,
             }
-
-            if min_dist < threshold {
-                matched_count += 1;
-                debug!(
-                    "Match Cluster {} (distance: {:.4}) -> {preprocessed_message}",
-                    closest_cluster_index, min_dist
-                );
-                let mut matched_centroid = centroids.slice_mut(s![closest_cluster_index, ..]);
-                let update = &(&message_embedding - &matched_centroid) * learning_rate as f32;
-                matched_centroid += &update;
-            } else {
-                debug!(
-                    "No match (distance: {:.4}) -> {preprocessed_message}",
-                    min_dist
-                );
-                writeln!(unmatched_writer, "{original_line}")?;
+            if let Some(follow) = &follow {
+                if last_checkpoint.elapsed() >= follow.checkpoint_interval {
+                    ingester.checkpoint(centroids_file, input_file, reader.position())?;
+                    debug!(
+                        "Checkpoint: {} of {} messages matched so far.",
+                        ingester.matched_count, ingester.total_count
+                    );
+                    last_checkpoint = Instant::now();
+                }
             }
-            Ok(())
-        },
-    )?;
+        }
+        position = reader.position();
+    }
 
     info!("Ingestion complete.");
-    info!("{} messages matched and updated centroids.", matched_count);
+    info!(
+        "{} messages matched and updated centroids.",
+        ingester.matched_count
+    );
     info!(
         "{} messages did not match and were written to {}.",
-        total_count - matched_count,
+        ingester.total_count - ingester.matched_count,
         unmatched_file
     );
 
-    save_centroids(&centroids, centroids_file)?;
+    ingester.checkpoint(centroids_file, input_file, position)?;
     info!("Centroids file updated.");
 
     Ok(())
 }
 
+/// Tuning parameters for `retrain`.
+pub struct RetrainOptions {
+    /// Lines within this distance of an existing centroid are already covered by the model.
+    pub threshold: MatchThreshold,
+    /// The epsilon for grouping the new lines, or `None` to use the one the model was trained
+    /// with. It is required if the model records none, unless it was trained with HDBSCAN.
+    pub epsilon: Option<f32>,
+    /// The minimum cluster size for grouping the new lines, or `None` to use the one the model
+    /// was trained with.
+    pub min_points: Option<usize>,
+    /// Add a centroid for every message left as noise, instead of only for those standing for
+    /// at least `min_points` lines.
+    pub keep_noise: bool,
+    /// Continue with a warning if the model was built with a different embedding model or patterns.
+    pub allow_mismatch: bool,
+    /// The nearest-neighbour index file to search and add the new centroids to, or `None` to
+    /// compare every message with all centroids.
+    pub index_file: Option<String>,
+}
+
 /// Retrains the model by creating new centroids from a log file.
 ///
-/// This function is used to incorporate previously unmatched logs into the model.
+/// This function is used to incorporate previously unmatched logs into the model. Lines are
+/// deduplicated by their preprocessed text, and messages already within the threshold of an
+/// existing centroid only count as hits of that cluster. The remaining messages are grouped with
+/// the clustering algorithm the model was trained with, and one centroid is added per group.
+/// Messages left as noise get a centroid of their own only if they stand for at least
+/// `min_points` lines, or if `keep_noise` is set; the others are skipped, so that a noisy input
+/// does not add one centroid per line.
 ///
 /// # Arguments
 ///
-/// * `input_file` - The path to the log file to create new centroids from.
+/// * `input_file` - The path to the log file to create new centroids from, or `-` for standard input.
 /// * `centroids_file` - The path to the centroids file to update.
+/// * `options` - The `RetrainOptions` controlling matching and clustering.
 /// * `preprocessor` - The `LogPreprocessor` to apply to each log message.
+/// * `model` - The embedder turning log messages into vectors.
 pub fn retrain(
     input_file: &str,
     centroids_file: &str,
+    options: &RetrainOptions,
     preprocessor: &LogPreprocessor,
+    model: &mut impl Embedder,
     _verbose: bool,
 ) -> Result<()> {
-    let mut model = EmbeddingModel::load()?;
+    let identity = ModelIdentity::new(model, preprocessor);
 
     info!("Loading existing centroids from {}...", centroids_file);
-    let file = File::open(centroids_file)?;
-    let centroids: Array2<f32> = serde_json::from_reader(file)?;
+    let mut log_model = LogModel::load(centroids_file)?;
+    log_model.check_compatible(&identity, options.allow_mismatch)?;
+    let algorithm = log_model.metadata.algorithm;
+    let epsilon = match (algorithm, options.epsilon.or(log_model.metadata.epsilon)) {
+        (_, Some(epsilon)) => epsilon,
+        // HDBSCAN does not use epsilon.
+        (ClusteringAlgorithm::Hdbscan, None) => 0.0,
+        (_, None) => {
+            return Err(E::msg(format!(
+                "The model records no epsilon for {algorithm} clustering; pass --epsilon"
+            )))
+        }
+    };
+    let min_points = options
+        .min_points
+        .or(log_model.metadata.min_points)
+        .unwrap_or(3);
+    let mut index = match &options.index_file {
+        Some(index_file) => Some(HnswIndex::open(index_file, &log_model)?),
+        None => None,
+    };
 
     info!("Reading and parsing new training data from {}", input_file);
-    let mut sentences = Vec::new();
-    process_log_file(
-        input_file,
-        preprocessor,
-        |_original_line, preprocessed_message| {
-            debug!("Adding new centroid from: '{}'", preprocessed_message);
-            sentences.push(preprocessed_message);
-            Ok(())
-        },
-    )?;
-
-    if sentences.is_empty() {
+    let (messages, total_lines) = read_messages(input_file, preprocessor, None)?;
+
+    if messages.is_empty() {
         warn!("Input file is empty. No new centroids to add.");
         return Ok(());
     }
 
-    let sentences_str: Vec<&str> = sentences.iter().map(|s| s.as_str()).collect();
+    info!(
+        "Generating embeddings for {} distinct messages out of {} lines...",
+        messages.len(),
+        total_lines
+    );
+    let embeddings = embed_messages(model, &messages)?;
+
+    let now = Utc::now();
+    let mut covered = 0;
+    let mut new_rows = Vec::new();
+    for (i, message) in messages.iter().enumerate() {
+        match find_nearest(&log_model, index.as_ref(), embeddings.row(i))? {
+            Some(nearest)
+                if nearest.distance
+                    < options
+                        .threshold
+                        .for_cluster(&log_model.clusters[nearest.index]) =>
+            {
+                let cluster = &mut log_model.clusters[nearest.index];
+                debug!(
+                    "Already covered by cluster {} (distance: {:.4}): {}",
+                    cluster.display_name(),
+                    nearest.distance,
+                    message.text
+                );
+                for line in &message.exemplars {
+                    cluster.record_hit(line, now);
+                }
+                cluster.hits += message.count - message.exemplars.len() as u64;
+                covered += 1;
+            }
+            _ => new_rows.push(i),
+        }
+    }
+
+    let mut groups: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+    let mut skipped_messages = 0;
+    let mut skipped_lines = 0;
+    if !new_rows.is_empty() {
+        let new_embeddings = embeddings.select(Axis(0), &new_rows);
+        let new_weights: Vec<u64> = new_rows.iter().map(|&i| messages[i].count).collect();
+        let clusters = run_clustering(
+            &new_embeddings,
+            &new_weights,
+            algorithm,
+            epsilon,
+            min_points,
+            log_model.metadata.metric,
+        );
+        for (&i, cluster) in new_rows.iter().zip(clusters) {
+            if cluster.is_none() && !options.keep_noise && messages[i].count < min_points as u64 {
+                skipped_messages += 1;
+                skipped_lines += messages[i].count;
+                continue;
+            }
+            // Noise is keyed after all clusters, one group per message.
+            let key = cluster.map_or((1, i), |id| (0, id));
+            groups.entry(key).or_default().push(i);
+        }
+    }
+    if skipped_messages > 0 {
+        warn!(
+            "Skipped {skipped_messages} noise messages ({skipped_lines} lines) standing for fewer \
+             than {min_points} lines; pass --keep-noise to add a centroid for each."
+        );
+    }
+
+    let noise_groups = groups.keys().filter(|(noise, _)| *noise == 1).count();
+    for members in groups.values() {
+        let cluster = add_message_cluster(&mut log_model, &embeddings, &messages, members, now)?;
+        info!(
+            "New cluster {} from {} lines ({} distinct)",
+            cluster.display_name(),
+            cluster.hits,
+            members.len()
+        );
+        if let Some(index) = &mut index {
+            index.insert(&log_model.centroids)?;
+        }
+    }
+    log_model.touch(&identity);
+    log_model.save(centroids_file)?;
+    if let (Some(index), Some(index_file)) = (&mut index, &options.index_file) {
+        index.save(index_file, &log_model.centroids)?;
+    }
+    model.flush()?;
 
     info!(
-        "Generating embeddings for {} new log messages...",
-        sentences.len()
+        "{} of {} distinct messages were already covered by existing clusters.",
+        covered,
+        messages.len()
+    );
+    info!(
+        "Successfully added {} new centroids ({} from {} clusters, {} from single messages). Total centroids: {}",
+        groups.len(),
+        groups.len() - noise_groups,
+        algorithm.to_string().to_uppercase(),
+        noise_groups,
+        log_model.centroids.nrows()
     );
-    let embeddings_tensor = model.embed(&sentences_str)?;
-    let (num_sentences, num_dims) = embeddings_tensor.dims2()?;
-    let embeddings_vec: Vec<f32> = embeddings_tensor.flatten_all()?.to_vec1()?;
-    let new_centroids_array = Array2::from_shape_vec((num_sentences, num_dims), embeddings_vec)?;
 
-    let updated_centroids = concatenate(Axis(0), &[centroids.view(), new_centroids_array.view()])
-        .map_err(|e| E::msg(e.to_string()))?;
+    Ok(())
+}
+
+/// Counts of lines classified by `classify`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassifySummary {
+    /// The number of lines classified.
+    pub total: usize,
+    /// The number of lines within the threshold of a cluster.
+    pub matched: usize,
+}
+
+/// Tuning parameters for `classify`.
+pub struct ClassifyOptions {
+    /// The distance threshold for matching a cluster.
+    pub threshold: MatchThreshold,
+    /// Continue with a warning if the model was built with a different embedding model or patterns.
+    pub allow_mismatch: bool,
+    /// The `TimestampExtractor` used to find the timestamp of each log message.
+    pub timestamps: TimestampExtractor,
+    /// The format of the result written for every line.
+    pub output_format: OutputFormat,
+    /// The nearest-neighbour index file to search, or `None` to compare every message with all
+    /// centroids. A missing or outdated index is built in memory but not saved.
+    pub index_file: Option<String>,
+}
+
+/// Classifies each line of a log file against the model without modifying anything.
+///
+/// For every line it writes a result record to standard output. In text format this is the
+/// verdict (`matched` or `unmatched`), the nearest cluster, the distance to its centroid and the
+/// original line, separated by tabs.
+///
+/// # Arguments
+///
+/// * `input_file` - The path to the log file to classify, or `-` for standard input.
+/// * `centroids_file` - The path to the centroids file.
+/// * `options` - The `ClassifyOptions` controlling matching and output.
+/// * `preprocessor` - The `LogPreprocessor` to apply to each log message.
+/// * `model` - The embedder turning log messages into vectors. It is never flushed, so an
+///   embedding cache is read but not written.
+pub fn classify(
+    input_file: &str,
+    centroids_file: &str,
+    mut options: ClassifyOptions,
+    preprocessor: &LogPreprocessor,
+    model: &mut impl Embedder,
+) -> Result<ClassifySummary> {
+    let identity = ModelIdentity::new(model, preprocessor);
 
-    save_centroids(&updated_centroids, centroids_file)?;
+    info!("Loading centroids from {}...", centroids_file);
+    let log_model = LogModel::load(centroids_file)?;
+    log_model.check_compatible(&identity, options.allow_mismatch)?;
+    let index = match &options.index_file {
+        Some(index_file) => Some(HnswIndex::open(index_file, &log_model)?),
+        None => None,
+    };
+
+    let mut summary = ClassifySummary::default();
+    let mut nearest_by_message: HashMap<String, Option<Nearest>> = HashMap::new();
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    for original_line in open_input(input_file)?.lines() {
+        let original_line = original_line?;
+        let (preprocessed_message, params) = preprocessor.preprocess_with_params(&original_line);
+        let nearest = match nearest_by_message.get(&preprocessed_message) {
+            Some(&nearest) => nearest,
+            None => {
+                let embedding = embed_one(model, &preprocessed_message)?;
+                let nearest = find_nearest(&log_model, index.as_ref(), embedding.view())?;
+                nearest_by_message.insert(preprocessed_message.clone(), nearest);
+                nearest
+            }
+        };
+        summary.total += 1;
+        let verdict = match nearest {
+            Some(nearest)
+                if nearest.distance
+                    < options
+                        .threshold
+                        .for_cluster(&log_model.clusters[nearest.index]) =>
+            {
+                summary.matched += 1;
+                Verdict::Matched
+            }
+            _ => Verdict::Unmatched,
+        };
+        MatchRecord::new(
+            &original_line,
+            &preprocessed_message,
+            &params,
+            options.timestamps.extract(&original_line),
+            nearest.as_ref(),
+            &log_model,
+            verdict,
+        )
+        .write(options.output_format, &mut out)?;
+    }
+    out.flush()?;
 
     info!(
-        "Successfully added {} new centroids. Total centroids: {}",
-        new_centroids_array.nrows(),
-        updated_centroids.nrows()
+        "{} of {} lines matched a cluster.",
+        summary.matched, summary.total
     );
+    Ok(summary)
+}
 
+/// Sets or clears the human-readable label of a cluster.
+///
+/// # Arguments
+///
+/// * `centroids_file` - The path to the centroids file to update.
+/// * `cluster_id` - The stable ID of the cluster to label.
+/// * `label` - The new label, or `None` to remove the existing one.
+pub fn label(centroids_file: &str, cluster_id: u64, label: Option<&str>) -> Result<()> {
+    let mut log_model = LogModel::load(centroids_file)?;
+    let cluster = log_model.cluster_mut(cluster_id).ok_or_else(|| {
+        E::msg(format!(
+            "No cluster with ID {cluster_id} in {centroids_file}"
+        ))
+    })?;
+    cluster.label = label.map(str::to_string);
+    info!("Cluster {} labelled.", cluster.display_name());
+    log_model.metadata.updated_at = Utc::now();
+    log_model.save(centroids_file)?;
+    Ok(())
+}
+
+/// Prints the clusters of a model together with their statistics, most frequent parameter
+/// values and exemplar lines.
+///
+/// # Arguments
+///
+/// * `centroids_file` - The path to the centroids file.
+pub fn list_clusters(centroids_file: &str) -> Result<()> {
+    let log_model = LogModel::load(centroids_file)?;
+    let format_time =
+        |time: Option<DateTime<Utc>>| time.map_or_else(|| "-".to_string(), |t| t.to_rfc3339());
+    for cluster in &log_model.clusters {
+        println!(
+            "Cluster {}: {} hits, radius {}, first seen {}, last seen {}",
+            cluster.display_name(),
+            cluster.hits,
+            cluster
+                .radius
+                .map_or_else(|| "-".to_string(), |r| format!("{r:.4}")),
+            format_time(cluster.first_seen),
+            format_time(cluster.last_seen)
+        );
+        for (name, values) in &cluster.params {
+            let top = values.top(5);
+            let listed: u64 = top.iter().map(|&(_, seen)| seen).sum();
+            let rest = values.counts.values().sum::<u64>() - listed + values.other;
+            let mut top: Vec<String> = top
+                .into_iter()
+                .map(|(value, seen)| format!("{value} ({seen})"))
+                .collect();
+            if rest > 0 {
+                top.push(format!("{rest} other"));
+            }
+            println!("  {name}: {}", top.join(", "));
+        }
+        for exemplar in &cluster.exemplars {
+            println!("    {exemplar}");
+        }
+    }
     Ok(())
 }
 
+/// Settings for `tune`.
+pub struct TuneOptions {
+    /// The maximum number of log lines to embed and analyse.
+    pub sample_size: usize,
+    /// The DBSCAN minimum cluster sizes to evaluate.
+    pub min_points: Vec<usize>,
+    /// The distance metric the model will be trained with.
+    pub metric: DistanceMetric,
+}
+
+/// Suggests DBSCAN parameters for `train` by analysing a random sample of a log file.
+///
+/// For every candidate `min_points`, it computes the distance from each sampled message to its
+/// `min_points - 1`-th nearest neighbour and takes the knee of that curve as epsilon. It then
+/// clusters the sample with a grid of epsilons around the knee and prints the number of clusters
+/// and the fraction of noise for each. The recommendation is the knee for the smallest
+/// `min_points` that finds any cluster.
+///
+/// # Arguments
+///
+/// * `input_file` - The path to the log file to analyse, or `-` for standard input.
+/// * `options` - The `TuneOptions` controlling the sample and the candidates.
+/// * `preprocessor` - The `LogPreprocessor` to apply to each log message.
+/// * `model` - The embedder turning log messages into vectors.
+pub fn tune(
+    input_file: &str,
+    options: &TuneOptions,
+    preprocessor: &LogPreprocessor,
+    model: &mut impl Embedder,
+) -> Result<Vec<TuneCandidate>> {
+    if options.sample_size < 2 {
+        return Err(E::msg("The sample must contain at least 2 lines"));
+    }
+    let mut sample = Reservoir::new(options.sample_size, 0);
+    process_log_file(input_file, preprocessor, |_, preprocessed_message| {
+        sample.offer(preprocessed_message);
+        Ok(())
+    })?;
+    let total_lines = sample.seen();
+    let messages = sample.into_items();
+    if messages.len() < 2 {
+        return Err(E::msg(
+            "The input needs at least 2 lines to suggest parameters",
+        ));
+    }
+
+    info!(
+        "Generating embeddings for a sample of {} of {} lines...",
+        messages.len(),
+        total_lines
+    );
+    let texts: Vec<&str> = messages.iter().map(String::as_str).collect();
+    let embeddings = embed_texts(model, &texts)?;
+    model.flush()?;
+
+    let weights = vec![1; messages.len()];
+    let mut candidates = Vec::new();
+    for &min_points in &options.min_points {
+        let curve = k_distances(&embeddings, min_points.saturating_sub(1), options.metric)?;
+        let Some(knee) = find_knee(&curve) else {
+            warn!("The {min_points}-distance curve has no knee; skipping min_points={min_points}.");
+            continue;
+        };
+        let knee_epsilon = curve[knee] as f32;
+        for factor in EPSILON_FACTORS {
+            let epsilon = knee_epsilon * factor;
+            let clusters = run_clustering(
+                &embeddings,
+                &weights,
+                ClusteringAlgorithm::Dbscan,
+                epsilon,
+                min_points,
+                options.metric,
+            );
+            let noise = clusters.iter().filter(|c| c.is_none()).count();
+            candidates.push(TuneCandidate {
+                min_points,
+                epsilon,
+                at_knee: factor == 1.0,
+                clusters: clusters.iter().flatten().collect::<HashSet<_>>().len(),
+                noise_fraction: noise as f64 / clusters.len() as f64,
+            });
+        }
+    }
+
+    println!(
+        "Sample of {} of {} lines ({} distance):",
+        messages.len(),
+        total_lines,
+        options.metric
+    );
+    println!("min_points  epsilon   clusters  noise");
+    for candidate in &candidates {
+        println!(
+            "{:>10}  {:<8.4}  {:>8}  {:>5.1}%{}",
+            candidate.min_points,
+            candidate.epsilon,
+            candidate.clusters,
+            100.0 * candidate.noise_fraction,
+            if candidate.at_knee { "  (knee)" } else { "" }
+        );
+    }
+    match candidates.iter().find(|c| c.at_knee && c.clusters > 0) {
+        Some(best) => println!(
+            "Recommended: --epsilon {:.4} --min-points {} (about {} clusters and {:.1}% noise in the sample)",
+            best.epsilon,
+            best.min_points,
+            best.clusters,
+            100.0 * best.noise_fraction
+        ),
+        None => println!("No candidate found any clusters; try more lines or smaller min_points."),
+    }
+    Ok(candidates)
+}
+
+/// The number of lines untouched by all patterns that `test_patterns` lists.
+const UNTOUCHED_EXAMPLES: usize = 10;
+
+/// The ANSI escape codes highlighting replaced text, inserted text and ending a highlight.
+const HIGHLIGHT_REPLACED: &str = "\x1b[1;31m";
+const HIGHLIGHT_INSERTED: &str = "\x1b[1;32m";
+const HIGHLIGHT_END: &str = "\x1b[0m";
+
+/// Returns a hash of a text, used to count distinct lines without keeping them.
+fn text_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Surrounds the given byte ranges of a text with an ANSI highlight.
+fn highlight(text: &str, ranges: &[Range<usize>], color: &str) -> String {
+    let mut highlighted = String::with_capacity(text.len());
+    let mut end = 0;
+    for range in ranges {
+        highlighted.push_str(&text[end..range.start]);
+        highlighted.push_str(color);
+        highlighted.push_str(&text[range.clone()]);
+        highlighted.push_str(HIGHLIGHT_END);
+        end = range.end;
+    }
+    highlighted.push_str(&text[end..]);
+    highlighted
+}
+
+/// The matches of a single pattern, collected by `test_patterns`.
+#[derive(Default)]
+struct PatternHits {
+    /// The number of replaced matches.
+    matches: usize,
+    /// The number of lines with at least one match.
+    lines: usize,
+    /// The hashes of the distinct lines after applying this and all earlier patterns.
+    distinct_after: HashSet<u64>,
+}
+
+/// How much a set of patterns matches and normalizes the lines of a log file.
+struct PatternCoverage {
+    /// The number of lines read.
+    lines: usize,
+    /// The hashes of the distinct lines before preprocessing.
+    distinct_before: HashSet<u64>,
+    /// The matches of each pattern, in the order the patterns are applied.
+    hits: Vec<PatternHits>,
+    /// The number of lines no pattern matched.
+    untouched: usize,
+    /// The first lines no pattern matched.
+    untouched_examples: Vec<String>,
+}
+
+impl PatternCoverage {
+    fn new(patterns: usize) -> Self {
+        Self {
+            lines: 0,
+            distinct_before: HashSet::new(),
+            hits: (0..patterns).map(|_| PatternHits::default()).collect(),
+            untouched: 0,
+            untouched_examples: Vec::new(),
+        }
+    }
+
+    /// Records the patterns that matched a line.
+    fn add(&mut self, line: &str, trace: &Trace) {
+        self.lines += 1;
+        self.distinct_before.insert(text_hash(line));
+        if trace.steps.is_empty() {
+            self.untouched += 1;
+            if self.untouched_examples.len() < UNTOUCHED_EXAMPLES {
+                self.untouched_examples.push(line.to_string());
+            }
+        }
+        let mut steps = trace.steps.iter().peekable();
+        let mut text = line;
+        for (index, hits) in self.hits.iter_mut().enumerate() {
+            if let Some(step) = steps.next_if(|step| step.pattern == index) {
+                hits.matches += step.matches;
+                hits.lines += 1;
+                text = &step.text;
+            }
+            hits.distinct_after.insert(text_hash(text));
+        }
+    }
+
+    /// Returns the number of distinct lines after applying all patterns.
+    fn distinct_after(&self) -> usize {
+        self.hits
+            .last()
+            .map_or(self.distinct_before.len(), |hits| hits.distinct_after.len())
+    }
+
+    /// Prints the matches of each pattern, the patterns that never matched, the lines no pattern
+    /// matched and the number of distinct lines before and after preprocessing.
+    fn print(&self, names: &[&str]) {
+        println!("Pattern coverage on {} lines:", self.lines);
+        println!(
+            "{:>4}  {:<24} {:>8} {:>8} {:>9} {:>9}",
+            "#", "pattern", "matches", "lines", "distinct", "collapsed"
+        );
+        let mut distinct = self.distinct_before.len();
+        for (index, (name, hits)) in names.iter().zip(&self.hits).enumerate() {
+            let collapsed = distinct - hits.distinct_after.len();
+            distinct = hits.distinct_after.len();
+            println!(
+                "{:>4}  {:<24} {:>8} {:>8} {:>9} {:>9}",
+                index + 1,
+                name,
+                hits.matches,
+                hits.lines,
+                distinct,
+                collapsed
+            );
+        }
+
+        let unmatched: Vec<&str> = names
+            .iter()
+            .zip(&self.hits)
+            .filter(|(_, hits)| hits.matches == 0)
+            .map(|(name, _)| *name)
+            .collect();
+        if !unmatched.is_empty() {
+            println!(
+                "Patterns that never matched ({}): {}",
+                unmatched.len(),
+                unmatched.join(", ")
+            );
+        }
+        if self.untouched > 0 {
+            println!("Lines no pattern matched: {}", self.untouched);
+            for line in &self.untouched_examples {
+                println!("    {line}");
+            }
+            if self.untouched > self.untouched_examples.len() {
+                println!("    ...");
+            }
+        }
+        let before = self.distinct_before.len();
+        let after = self.distinct_after();
+        println!(
+            "Distinct lines: {before} before preprocessing, {after} after ({:.1}% fewer)",
+            if before == 0 {
+                0.0
+            } else {
+                100.0 * (before - after) as f64 / before as f64
+            }
+        );
+    }
+}
+
 /// Tests the regex patterns on a log file.
 ///
 /// This function is a utility to help with debugging and refining the regex patterns.
-/// It logs the original and preprocessed versions of each line in a log file.
+/// It prints each line as it was (`-`) and after preprocessing (`+`), highlighting the replaced
+/// text when writing to a terminal, or once (`=`) if no pattern matched it. It then reports how
+/// often each pattern matched, how many distinct lines remained after it, the patterns that
+/// never matched and the lines no pattern matched.
 ///
 /// # Arguments
 ///
-/// * `input_file` - The path to the log file to test patterns on.
+/// * `input_file` - The path to the log file to test patterns on, or `-` for standard input.
 /// * `preprocessor` - The `LogPreprocessor` to apply to each log message.
 pub fn test_patterns(input_file: &str, preprocessor: &LogPreprocessor) -> Result<()> {
     info!("Testing patterns on log file: {}", input_file);
-    let file = File::open(input_file)?;
-    let reader = BufReader::new(file);
-    for line in reader.lines() {
+    let color = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let names = preprocessor.pattern_names();
+    let mut coverage = PatternCoverage::new(names.len());
+    for line in open_input(input_file)?.lines() {
         let line = line?;
-        let preprocessed = preprocessor.preprocess(&line);
-        println!("{preprocessed}\n");
+        let trace = preprocessor.preprocess_traced(&line);
+        if trace.steps.is_empty() {
+            println!("= {line}");
+        } else if color {
+            println!(
+                "- {}",
+                highlight(&line, &trace.replaced, HIGHLIGHT_REPLACED)
+            );
+            println!(
+                "+ {}",
+                highlight(&trace.text, &trace.inserted, HIGHLIGHT_INSERTED)
+            );
+        } else {
+            println!("- {line}");
+            println!("+ {}", trace.text);
+        }
+        coverage.add(&line, &trace);
     }
+    println!();
+    coverage.print(&names);
     Ok(())
 }
+
+/// Prints the built-in patterns with their group and description.
+pub fn list_builtin_patterns() {
+    println!("Built-in patterns, version {BUILTIN_PATTERNS_VERSION}:");
+    for pattern in BUILTIN_PATTERNS {
+        println!(
+            "{:<15} {:<12} {}",
+            pattern.name, pattern.group, pattern.description
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CachedEmbedder;
+    use crate::embedding::{write_test_model, EmbeddingModel};
+    use crate::hashing::HashingEmbedder;
+    use std::path::Path;
+    use tempfile::{tempdir, TempDir};
+
+    /// Creates a temporary directory with a patterns file replacing numbers, and returns it
+    /// with the preprocessor, the embedder and their model identity.
+    ///
+    /// # Arguments
+    ///
+    /// * `load_model` - Creates the embedder, given the temporary directory.
+    fn fixture<M: Embedder>(
+        load_model: impl FnOnce(&Path) -> Result<M>,
+    ) -> Result<(TempDir, LogPreprocessor, M, ModelIdentity)> {
+        let dir = tempdir()?;
+        std::fs::write(dir.path().join("patterns.txt"), "\\d+ :: <NUM>\n")?;
+        let preprocessor = LogPreprocessor::new(&path(&dir, "patterns.txt"))?;
+        let model = load_model(dir.path())?;
+        let identity = ModelIdentity::new(&model, &preprocessor);
+        Ok((dir, preprocessor, model, identity))
+    }
+
+    /// Returns the path of a file in a test directory.
+    fn path(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_pattern_coverage() -> Result<()> {
+        let preprocessor = LogPreprocessor::builtin(&["mac", "ipv4", "number"])?;
+        let lines = [
+            "connection from 10.0.0.1 port 22",
+            "connection from 10.0.0.2 port 23",
+            "connection closed",
+            "connection closed",
+            "retry 1 of 3",
+        ];
+        let mut coverage = PatternCoverage::new(preprocessor.pattern_names().len());
+        for line in lines {
+            coverage.add(line, &preprocessor.preprocess_traced(line));
+        }
+
+        assert_eq!(coverage.lines, 5);
+        assert_eq!(coverage.untouched, 2);
+        assert_eq!(coverage.untouched_examples, ["connection closed"; 2]);
+        let hits: Vec<(usize, usize, usize)> = coverage
+            .hits
+            .iter()
+            .map(|hits| (hits.matches, hits.lines, hits.distinct_after.len()))
+            .collect();
+        // The connection lines only collapse once both addresses and numbers are replaced.
+        assert_eq!(hits, [(0, 0, 4), (2, 2, 4), (4, 3, 3)]);
+        assert_eq!(coverage.distinct_before.len(), 4);
+        assert_eq!(coverage.distinct_after(), 3);
+
+        assert_eq!(
+            highlight("port 22", &[0..1, 5..7], HIGHLIGHT_INSERTED),
+            "\x1b[1;32mp\x1b[0mort \x1b[1;32m22\x1b[0m"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_retrain_adds_one_centroid_per_new_cluster() -> Result<()> {
+        let (dir, preprocessor, mut model, identity) = fixture(|_| HashingEmbedder::new(64))?;
+        let mut log_model = LogModel::new(64, &identity, 0.5, 2, DistanceMetric::Euclidean);
+        log_model.add_cluster(embed_one(&mut model, "disk full on sda")?.view())?;
+        log_model.save(&path(&dir, "centroids.json"))?;
+
+        let unmatched = [
+            "disk full on sda",
+            "user 1 logged in",
+            "user 2 logged in",
+            "user 3 logged in",
+            "session opened for alice by root",
+            "session opened for bob by root",
+            "session opened for carol by root",
+            "kernel panic",
+        ];
+        std::fs::write(path(&dir, "unmatched.log"), unmatched.join("\n"))?;
+        let options = RetrainOptions {
+            threshold: MatchThreshold::Global(0.5),
+            epsilon: Some(0.9),
+            min_points: None,
+            keep_noise: false,
+            allow_mismatch: false,
+            index_file: None,
+        };
+        retrain(
+            &path(&dir, "unmatched.log"),
+            &path(&dir, "centroids.json"),
+            &options,
+            &preprocessor,
+            &mut model,
+            false,
+        )?;
+
+        let log_model = LogModel::load(&path(&dir, "centroids.json"))?;
+        let hits: Vec<u64> = log_model.clusters.iter().map(|c| c.hits).collect();
+        // The sessions form one DBSCAN cluster and the repeated user message is a single
+        // message; the kernel panic is noise standing for fewer than min_points lines.
+        assert_eq!(hits, [1, 3, 3]);
+        assert_eq!(log_model.clusters[2].exemplars.len(), 3);
+        // Only the cluster of several distinct messages has a spread to measure.
+        assert!(log_model.clusters[2].radius.is_some_and(|r| r > 0.0));
+        assert_eq!(
+            log_model.clusters[2].template.as_deref(),
+            Some("session opened for <*> by root")
+        );
+        assert_eq!(log_model.clusters[1].radius, None);
+
+        // With keep_noise, the noise message gets a centroid of its own.
+        let options = RetrainOptions {
+            keep_noise: true,
+            ..options
+        };
+        retrain(
+            &path(&dir, "unmatched.log"),
+            &path(&dir, "centroids.json"),
+            &options,
+            &preprocessor,
+            &mut model,
+            false,
+        )?;
+        let log_model = LogModel::load(&path(&dir, "centroids.json"))?;
+        assert_eq!(log_model.clusters.len(), 4);
+        assert_eq!(log_model.clusters[3].exemplars, ["kernel panic"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_retrain_groups_with_the_recorded_algorithm() -> Result<()> {
+        let (dir, preprocessor, mut model, identity) = fixture(|_| HashingEmbedder::new(64))?;
+        std::fs::write(
+            path(&dir, "unmatched.log"),
+            "kernel panic\nuser 1 logged in\n",
+        )?;
+        let options = RetrainOptions {
+            threshold: MatchThreshold::Global(0.5),
+            epsilon: None,
+            min_points: None,
+            keep_noise: false,
+            allow_mismatch: false,
+            index_file: None,
+        };
+
+        for (algorithm, succeeds) in [
+            (ClusteringAlgorithm::Hdbscan, true),
+            (ClusteringAlgorithm::Dbscan, false),
+        ] {
+            let mut log_model = LogModel::new(64, &identity, 0.5, 2, DistanceMetric::Euclidean);
+            log_model.metadata.algorithm = algorithm;
+            log_model.metadata.epsilon = None;
+            log_model.save(&path(&dir, "centroids.json"))?;
+            let result = retrain(
+                &path(&dir, "unmatched.log"),
+                &path(&dir, "centroids.json"),
+                &options,
+                &preprocessor,
+                &mut model,
+                false,
+            );
+            assert_eq!(result.is_ok(), succeeds, "{algorithm}");
+        }
+        assert_eq!(
+            LogModel::load(&path(&dir, "centroids.json"))?
+                .clusters
+                .len(),
+            0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_writes_no_files() -> Result<()> {
+        let (dir, preprocessor, mut model, identity) = fixture(|_| HashingEmbedder::new(64))?;
+        let mut log_model = LogModel::new(64, &identity, 0.5, 2, DistanceMetric::Euclidean);
+        log_model.add_cluster(embed_one(&mut model, "disk full on sda")?.view())?;
+        log_model.save(&path(&dir, "centroids.json"))?;
+        std::fs::write(
+            path(&dir, "new.log"),
+            "disk full on sda\nuser 1 logged in\n",
+        )?;
+        let files_before = std::fs::read_dir(dir.path())?.count();
+
+        let mut model = CachedEmbedder::open(
+            HashingEmbedder::new(64)?,
+            &dir.path().join("embeddings.cache"),
+            1 << 20,
+        )?;
+        let options = ClassifyOptions {
+            threshold: MatchThreshold::Global(0.5),
+            allow_mismatch: false,
+            timestamps: TimestampExtractor::default(),
+            output_format: OutputFormat::Text,
+            index_file: Some(path(&dir, "centroids.index")),
+        };
+        let summary = classify(
+            &path(&dir, "new.log"),
+            &path(&dir, "centroids.json"),
+            options,
+            &preprocessor,
+            &mut model,
+        )?;
+
+        assert_eq!((summary.matched, summary.total), (1, 2));
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), files_before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_ingest_matches_line_by_line_ingest() -> Result<()> {
+        let (dir, preprocessor, mut model, identity) = fixture(|dir| {
+            let model_dir = dir.join("model");
+            std::fs::create_dir(&model_dir)?;
+            write_test_model(&model_dir)?;
+            EmbeddingModel::load_from_dir(&model_dir)
+        })?;
+        let mut log_model = LogModel::new(8, &identity, 0.5, 2, DistanceMetric::Euclidean);
+        for message in ["disk full on sda", "user logged in"] {
+            log_model.add_cluster(embed_one(&mut model, message)?.view())?;
+        }
+        let log = [
+            "user logged in",
+            "error 1",
+            "disk full on sda",
+            "error 2",
+            "user logged out",
+            "user logged in",
+            "disk error on sda",
+        ];
+        std::fs::write(path(&dir, "new.log"), log.join("\n"))?;
+
+        let mut results = Vec::new();
+        for batch_size in [1, 3] {
+            let centroids_file = path(&dir, &format!("centroids-{batch_size}.json"));
+            let unmatched_file = path(&dir, &format!("unmatched-{batch_size}.log"));
+            log_model.save(&centroids_file)?;
+            let options = IngestOptions {
+                threshold: MatchThreshold::Global(0.05),
+                learning_rate: 0.1,
+                allow_mismatch: false,
+                timestamps: TimestampExtractor::default(),
+                follow: None,
+                checkpoint_file: None,
+                from_start: false,
+                output_format: None,
+                batch_size,
+                index_file: None,
+            };
+            ingest(
+                &path(&dir, "new.log"),
+                &centroids_file,
+                &unmatched_file,
+                options,
+                &preprocessor,
+                &mut model,
+                false,
+            )?;
+            let unmatched = std::fs::read_to_string(&unmatched_file)?;
+            results.push((LogModel::load(&centroids_file)?, unmatched));
+        }
+
+        let (line_by_line, unmatched) = &results[0];
+        let (batched, batched_unmatched) = &results[1];
+        assert_eq!(unmatched, batched_unmatched);
+        assert!(unmatched.contains("error 1") && !unmatched.contains("disk full on sda"));
+        let hits = |m: &LogModel| m.clusters.iter().map(|c| c.hits).collect::<Vec<_>>();
+        assert_eq!(hits(line_by_line), hits(batched));
+        assert_eq!(hits(batched), [1, 2]);
+        let diff = (&line_by_line.centroids - &batched.centroids).mapv(f32::abs);
+        assert!(diff.iter().all(|&d| d < 1e-5));
+        Ok(())
+    }
+}
@@ -1,64 +1,457 @@
+use crate::cache::{CachedEmbedder, DEFAULT_CACHE_MAX_BYTES};
+use crate::hashing::{HashingEmbedder, DEFAULT_DIMENSIONS};
+use crate::model::Fingerprinter;
 use anyhow::{Error as E, Result};
 use candle_core::{Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::bert::{BertModel, Config, DTYPE};
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use tokenizers::Tokenizer;
+use hf_hub::{api::sync::Api, Cache, Repo, RepoType};
+use log::debug;
+use ndarray::Array2;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+
+/// The Hugging Face Hub repository the embedding model is fetched from.
+pub const MODEL_REPO: &str = "sentence-transformers/all-MiniLM-L6-v2";
+
+/// The files that make up an embedding model.
+const MODEL_FILES: [&str; 3] = ["config.json", "tokenizer.json", "model.safetensors"];
+
+/// Where the embedding model files are loaded from.
+#[derive(Debug, Clone, Default)]
+pub enum ModelSource {
+    /// Download the model from the Hugging Face Hub, reusing the local cache when possible.
+    #[default]
+    Hub,
+    /// Use only the local Hugging Face cache and never touch the network.
+    HubOffline,
+    /// Read the model files from a local directory and never touch the network.
+    Dir(PathBuf),
+}
+
+/// Turns log messages into vectors, so that similar messages end up close to each other.
+pub trait Embedder {
+    /// Returns a human-readable name for the embedder.
+    fn name(&self) -> &str;
+
+    /// Returns a fingerprint that changes whenever the embedder would produce different vectors.
+    fn fingerprint(&self) -> &str;
+
+    /// Returns the number of dimensions of the embeddings.
+    fn dimensions(&self) -> usize;
+
+    /// Generates L2-normalized embeddings for a batch of sentences, one row per sentence.
+    ///
+    /// The embedding of a sentence must not depend on the other sentences in the batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `sentences` - A slice of string slices, where each string slice is a sentence to embed.
+    fn embed(&mut self, sentences: &[&str]) -> Result<Array2<f32>>;
+
+    /// Persists any state worth keeping across runs, such as cached embeddings.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<M: Embedder + ?Sized> Embedder for Box<M> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn fingerprint(&self) -> &str {
+        (**self).fingerprint()
+    }
+
+    fn dimensions(&self) -> usize {
+        (**self).dimensions()
+    }
+
+    fn embed(&mut self, sentences: &[&str]) -> Result<Array2<f32>> {
+        (**self).embed(sentences)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// The available embedding backends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmbedderBackend {
+    /// The MiniLM sentence transformer: accurate, but needs the model files and more CPU.
+    #[default]
+    MiniLm,
+    /// Feature hashing of the message tokens: tiny and fast, with no model files.
+    Hashing,
+}
+
+impl FromStr for EmbedderBackend {
+    type Err = E;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "minilm" => Ok(Self::MiniLm),
+            "hashing" => Ok(Self::Hashing),
+            _ => Err(E::msg(format!(
+                "Unknown embedder '{name}', expected minilm or hashing"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EmbedderBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::MiniLm => "minilm",
+            Self::Hashing => "hashing",
+        })
+    }
+}
+
+/// How the embedder is loaded and applied.
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingConfig {
+    /// The embedding backend.
+    pub backend: EmbedderBackend,
+    /// Where the model files are loaded from.
+    pub source: ModelSource,
+    /// The maximum number of tokens per sentence; longer sentences are truncated. Defaults to the
+    /// truncation length of the tokenizer, or else the longest input the model supports.
+    pub max_length: Option<usize>,
+    /// The number of dimensions of the hashing embedder. Defaults to `DEFAULT_DIMENSIONS`.
+    pub dimensions: Option<usize>,
+    /// The file caching embeddings across runs, or `None` to embed every message afresh.
+    pub cache_file: Option<PathBuf>,
+    /// The size limit of the cache file in bytes. Defaults to `DEFAULT_CACHE_MAX_BYTES`.
+    pub cache_max_bytes: Option<u64>,
+}
+
+impl EmbeddingConfig {
+    /// Loads the configured embedder, wrapped with the embedding cache if one is configured.
+    pub fn load(&self) -> Result<Box<dyn Embedder>> {
+        let embedder: Box<dyn Embedder> = match self.backend {
+            EmbedderBackend::MiniLm => Box::new(EmbeddingModel::load_with(self)?),
+            EmbedderBackend::Hashing => Box::new(HashingEmbedder::new(
+                self.dimensions.unwrap_or(DEFAULT_DIMENSIONS),
+            )?),
+        };
+        Ok(match &self.cache_file {
+            Some(cache_file) => Box::new(CachedEmbedder::open(
+                embedder,
+                cache_file,
+                self.cache_max_bytes.unwrap_or(DEFAULT_CACHE_MAX_BYTES),
+            )?),
+            None => embedder,
+        })
+    }
+}
+
+/// Resolves the paths of all model files using the given lookup function.
+fn locate_files<F>(mut locate: F) -> Result<[PathBuf; 3]>
+where
+    F: FnMut(&str) -> Result<PathBuf>,
+{
+    let [config, tokenizer, weights] = MODEL_FILES;
+    Ok([locate(config)?, locate(tokenizer)?, locate(weights)?])
+}
 
 /// A wrapper for the sentence embedding model.
 pub struct EmbeddingModel {
     model: BertModel,
     tokenizer: Tokenizer,
+    dimensions: usize,
+    name: String,
+    fingerprint: String,
 }
 
 impl EmbeddingModel {
     /// Loads the sentence embedding model and tokenizer from the Hugging Face Hub.
     pub fn load() -> Result<Self> {
+        Self::load_from(&ModelSource::Hub)
+    }
+
+    /// Loads the sentence embedding model and tokenizer from a local directory.
+    ///
+    /// The directory must contain `config.json`, `tokenizer.json` and `model.safetensors`.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_dir` - The directory containing the model files.
+    pub fn load_from_dir(model_dir: &Path) -> Result<Self> {
+        Self::load_from(&ModelSource::Dir(model_dir.to_path_buf()))
+    }
+
+    /// Loads the sentence embedding model and tokenizer from the given source.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Where to read the model files from.
+    pub fn load_from(source: &ModelSource) -> Result<Self> {
+        Self::load_with(&EmbeddingConfig {
+            source: source.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Loads the sentence embedding model and tokenizer as described by an `EmbeddingConfig`.
+    ///
+    /// # Arguments
+    ///
+    /// * `embedding_config` - Where to read the model files from and how to apply the model.
+    pub fn load_with(embedding_config: &EmbeddingConfig) -> Result<Self> {
+        let source = &embedding_config.source;
+        let name = match source {
+            ModelSource::Hub | ModelSource::HubOffline => MODEL_REPO.to_string(),
+            ModelSource::Dir(dir) => dir.display().to_string(),
+        };
+        let [config_filename, tokenizer_filename, weights_filename] = match source {
+            ModelSource::Hub => {
+                let api = Api::new()?;
+                let repo = api.repo(Repo::new(MODEL_REPO.to_string(), RepoType::Model));
+                locate_files(|name| Ok(repo.get(name)?))?
+            }
+            ModelSource::HubOffline => {
+                let cache = Cache::from_env();
+                let repo = cache.repo(Repo::new(MODEL_REPO.to_string(), RepoType::Model));
+                locate_files(|name| {
+                    repo.get(name).ok_or_else(|| {
+                        E::msg(format!(
+                            "{name} for {MODEL_REPO} is not in the local Hugging Face cache ({}); \
+                             run once without --offline or use --model-dir",
+                            cache.path().display()
+                        ))
+                    })
+                })?
+            }
+            ModelSource::Dir(dir) => {
+                let missing: Vec<&str> = MODEL_FILES
+                    .into_iter()
+                    .filter(|name| !dir.join(name).is_file())
+                    .collect();
+                if !missing.is_empty() {
+                    return Err(E::msg(format!(
+                        "Model directory {} is missing {}",
+                        dir.display(),
+                        missing.join(", ")
+                    )));
+                }
+                locate_files(|name| Ok(dir.join(name)))?
+            }
+        };
+
         let device = Device::Cpu;
-        let api = Api::new()?;
-        let repo = api.repo(Repo::new(
-            "sentence-transformers/all-MiniLM-L6-v2".to_string(),
-            RepoType::Model,
-        ));
-        let (config_filename, tokenizer_filename, weights_filename) = (
-            repo.get("config.json")?,
-            repo.get("tokenizer.json")?,
-            repo.get("model.safetensors")?,
-        );
-        let config = std::fs::read_to_string(config_filename)?;
-        let config: Config = serde_json::from_str(&config)?;
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+        let config_json = std::fs::read_to_string(config_filename)?;
+        let config: Config = serde_json::from_str(&config_json)?;
+        let mut tokenizer = Tokenizer::from_file(&tokenizer_filename).map_err(E::msg)?;
+
+        let max_length = embedding_config
+            .max_length
+            .or_else(|| tokenizer.get_truncation().map(|t| t.max_length))
+            .unwrap_or(config.max_position_embeddings)
+            .min(config.max_position_embeddings);
+        if max_length == 0 {
+            return Err(E::msg("The maximum sequence length must be at least 1"));
+        }
+        debug!("Truncating sentences to {max_length} tokens.");
+        let truncation = TruncationParams {
+            max_length,
+            ..tokenizer.get_truncation().cloned().unwrap_or_default()
+        };
+        tokenizer
+            .with_truncation(Some(truncation))
+            .map_err(E::msg)?;
+        // Pad each batch only to its longest sentence; padding is masked out anyway.
+        let padding = PaddingParams {
+            strategy: PaddingStrategy::BatchLongest,
+            ..tokenizer.get_padding().cloned().unwrap_or_default()
+        };
+        tokenizer.with_padding(Some(padding));
+
+        // The truncation length and pooling change the embeddings as much as the weights do.
+        // The files are streamed through the hash rather than read into memory.
+        let mut fingerprinter = Fingerprinter::default();
+        fingerprinter.add(config_json.as_bytes());
+        fingerprinter.add_file(&tokenizer_filename)?;
+        fingerprinter.add_file(&weights_filename)?;
+        fingerprinter.add(format!("masked-mean-pooling;max-length={max_length}").as_bytes());
+        let fingerprint = fingerprinter.finish();
         let vb =
             unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DTYPE, &device)? };
         let model = BertModel::load(vb, &config)?;
-        Ok(Self { model, tokenizer })
+        Ok(Self {
+            model,
+            tokenizer,
+            dimensions: config.hidden_size,
+            name,
+            fingerprint,
+        })
     }
+}
 
-    /// Generates embeddings for a batch of sentences.
-    ///
-    /// # Arguments
-    ///
-    /// * `sentences` - A slice of string slices, where each string slice is a sentence to embed.
-    pub fn embed(&mut self, sentences: &[&str]) -> Result<Tensor> {
+impl Embedder for EmbeddingModel {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Covers the model configuration, tokenizer, weights and truncation length. Identical model
+    /// files and settings yield the same fingerprint regardless of where they were loaded from.
+    fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    /// Each embedding is the mean of the token embeddings of its sentence, ignoring the padding
+    /// added to match the longest sentence of the batch, so it does not depend on the other
+    /// sentences in the batch. Sentences longer than the maximum length are truncated.
+    fn embed(&mut self, sentences: &[&str]) -> Result<Array2<f32>> {
         let device = &self.model.device;
-        let tokens = self
+        let encodings = self
             .tokenizer
             .encode_batch(sentences.to_vec(), true)
             .map_err(E::msg)?;
-        let token_ids = tokens
-            .iter()
-            .map(|tokens| {
-                let tokens = tokens.get_ids().to_vec();
-                Ok(Tensor::new(tokens.as_slice(), device)?)
-            })
-            .collect::<Result<Vec<_>>>()?;
+        let stack = |values: &dyn Fn(&tokenizers::Encoding) -> &[u32]| -> Result<Tensor> {
+            let rows = encodings
+                .iter()
+                .map(|encoding| Ok(Tensor::new(values(encoding), device)?))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Tensor::stack(&rows, 0)?)
+        };
+        let token_ids = stack(&|encoding| encoding.get_ids())?;
+        let token_type_ids = stack(&|encoding| encoding.get_type_ids())?;
+        let attention_mask = stack(&|encoding| encoding.get_attention_mask())?;
 
-        let token_ids = Tensor::stack(&token_ids, 0)?;
-        let token_type_ids = token_ids.zeros_like()?;
-        let embeddings = self.model.forward(&token_ids, &token_type_ids, None)?;
-        let (_n_sentence, n_tokens, _hidden_size) = embeddings.dims3()?;
-        let embeddings = (embeddings.sum(1)? / (n_tokens as f64))?;
+        let embeddings = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+        // Mean pooling over the real tokens only: (n_sentence, n_tokens, 1) mask.
+        let mask = attention_mask.to_dtype(embeddings.dtype())?.unsqueeze(2)?;
+        let sums = embeddings.broadcast_mul(&mask)?.sum(1)?;
+        let counts = mask.sum(1)?.clamp(1e-9, f64::INFINITY)?;
+        let embeddings = sums.broadcast_div(&counts)?;
         let embeddings = embeddings.broadcast_div(&embeddings.sqr()?.sum_keepdim(1)?.sqrt()?)?;
-        Ok(embeddings)
+
+        let (num_sentences, num_dims) = embeddings.dims2()?;
+        let values: Vec<f32> = embeddings.flatten_all()?.to_vec1()?;
+        Ok(Array2::from_shape_vec((num_sentences, num_dims), values)?)
+    }
+}
+
+/// Writes a tiny BERT model with fixed pseudo-random weights and a whitespace word-level
+/// tokenizer.
+#[cfg(test)]
+pub(crate) fn write_test_model(dir: &Path) -> Result<()> {
+    let words = [
+        "[PAD]", "[UNK]", "disk", "full", "on", "sda", "user", "logged", "in", "out", "error",
+    ];
+    let vocab: serde_json::Map<_, _> = words
+        .iter()
+        .enumerate()
+        .map(|(id, word)| (word.to_string(), id.into()))
+        .collect();
+    let tokenizer = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": null,
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]"}
+    });
+    std::fs::write(dir.join("tokenizer.json"), tokenizer.to_string())?;
+
+    let config = serde_json::json!({
+        "vocab_size": words.len(),
+        "hidden_size": 8,
+        "num_hidden_layers": 1,
+        "num_attention_heads": 2,
+        "intermediate_size": 16,
+        "hidden_act": "gelu",
+        "hidden_dropout_prob": 0.0,
+        "max_position_embeddings": 16,
+        "type_vocab_size": 2,
+        "initializer_range": 0.02,
+        "layer_norm_eps": 1e-12,
+        "pad_token_id": 0,
+        "classifier_dropout": null,
+        "model_type": "bert"
+    });
+    std::fs::write(dir.join("config.json"), config.to_string())?;
+
+    let varmap = candle_nn::VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DTYPE, &Device::Cpu);
+    BertModel::load(vb, &serde_json::from_value(config)?)?;
+    // Replace the random initialization with fixed weights, so that tests are reproducible.
+    let mut vars: Vec<_> = varmap.data().lock().unwrap().clone().into_iter().collect();
+    vars.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut state: u32 = 0x7e57;
+    for (name, var) in vars {
+        let offset = if name.ends_with("LayerNorm.weight") {
+            1.0
+        } else {
+            0.0
+        };
+        let values: Vec<f32> = (0..var.elem_count())
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                offset + (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+        var.set(&Tensor::from_vec(values, var.shape(), &Device::Cpu)?)?;
+    }
+    varmap.save(dir.join("model.safetensors"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn embed_rows(model: &mut EmbeddingModel, sentences: &[&str]) -> Result<Vec<Vec<f32>>> {
+        Ok(model
+            .embed(sentences)?
+            .outer_iter()
+            .map(|row| row.to_vec())
+            .collect())
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        let diff = a
+            .iter()
+            .zip(b)
+            .map(|(x, y)| (x - y).abs())
+            .fold(0.0, f32::max);
+        assert!(diff < 1e-5, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn test_embeddings_do_not_depend_on_batch_or_truncated_tokens() -> Result<()> {
+        let dir = tempdir()?;
+        write_test_model(dir.path())?;
+        let mut model = EmbeddingModel::load_with(&EmbeddingConfig {
+            source: ModelSource::Dir(dir.path().to_path_buf()),
+            max_length: Some(4),
+            ..Default::default()
+        })?;
+
+        let alone = embed_rows(&mut model, &["disk full"])?;
+        let batched = embed_rows(&mut model, &["user logged in on sda", "disk full"])?;
+        assert_close(&alone[0], &batched[1]);
+
+        // Only the first four tokens are embedded.
+        let truncated = embed_rows(&mut model, &["user logged in on sda disk full"])?;
+        assert_close(&truncated[0], &batched[0][..]);
+        let first_four = embed_rows(&mut model, &["user logged in on"])?;
+        assert_close(&truncated[0], &first_four[0]);
+        Ok(())
     }
 }
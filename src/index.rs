@@ -0,0 +1,518 @@
+use crate::model::{fingerprint, DistanceMetric, LogModel, Nearest};
+use crate::sampling::splitmix64;
+use anyhow::{Error as E, Result};
+use log::info;
+use ndarray::{Array2, ArrayView1};
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// The maximum number of links per node on the upper layers; layer 0 allows twice as many.
+const DEFAULT_M: usize = 16;
+
+/// The size of the candidate list when linking a new node.
+const DEFAULT_EF_CONSTRUCTION: usize = 100;
+
+/// The size of the candidate list when searching.
+const DEFAULT_EF_SEARCH: usize = 64;
+
+/// Returns the default index file for a centroids file, e.g. `centroids.hnsw.json` for
+/// `centroids.json`.
+///
+/// # Arguments
+///
+/// * `centroids_file` - The path to the centroids file.
+pub fn default_index_file(centroids_file: &str) -> String {
+    Path::new(centroids_file)
+        .with_extension("hnsw.json")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Computes the fingerprint of a centroid matrix, used to tell whether an index is up to date.
+///
+/// # Arguments
+///
+/// * `centroids` - The centroids, one per row.
+pub fn centroids_fingerprint(centroids: &Array2<f32>) -> String {
+    let bytes: Vec<u8> = centroids.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let shape = format!("{:?}", centroids.shape());
+    fingerprint([shape.as_bytes(), bytes.as_slice()])
+}
+
+/// A node found during a search, ordered by distance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f64,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+/// An approximate nearest-neighbour index over the centroids of a model, using a Hierarchical
+/// Navigable Small World (HNSW) graph.
+///
+/// The index only stores the graph; the vectors are always taken from the centroid matrix it
+/// was built for. It is kept up to date by inserting new centroids and relinking moved ones, and
+/// records a fingerprint of the centroids so that a stale index file is detected and rebuilt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HnswIndex {
+    metric: DistanceMetric,
+    m: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    /// For every node, its neighbours on each of its layers, starting at layer 0.
+    links: Vec<Vec<Vec<usize>>>,
+    entry_point: Option<usize>,
+    /// The state of the random generator choosing the layer of new nodes.
+    rng_state: u64,
+    /// The fingerprint of the centroids the index was last saved for.
+    centroids_fingerprint: String,
+}
+
+impl HnswIndex {
+    /// Creates an empty index.
+    ///
+    /// # Arguments
+    ///
+    /// * `metric` - The distance metric of the model.
+    pub fn new(metric: DistanceMetric) -> Self {
+        Self {
+            metric,
+            m: DEFAULT_M,
+            ef_construction: DEFAULT_EF_CONSTRUCTION,
+            ef_search: DEFAULT_EF_SEARCH,
+            links: Vec::new(),
+            entry_point: None,
+            rng_state: 0x5eed,
+            centroids_fingerprint: String::new(),
+        }
+    }
+
+    /// Builds an index over all centroids of a model.
+    ///
+    /// # Arguments
+    ///
+    /// * `log_model` - The model to index.
+    pub fn build(log_model: &LogModel) -> Result<Self> {
+        let mut index = Self::new(log_model.metadata.metric);
+        for _ in 0..log_model.centroids.nrows() {
+            index.insert(&log_model.centroids)?;
+        }
+        Ok(index)
+    }
+
+    /// Loads the index of a model from a file, or builds it if the file is missing or does not
+    /// match the current centroids.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the index file.
+    /// * `log_model` - The model the index is for.
+    pub fn open(path: &str, log_model: &LogModel) -> Result<Self> {
+        if Path::new(path).exists() {
+            let index: Self = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+            if index.metric == log_model.metadata.metric
+                && index.links.len() == log_model.centroids.nrows()
+                && index.centroids_fingerprint == centroids_fingerprint(&log_model.centroids)
+            {
+                info!("Loaded nearest-neighbour index from {path}.");
+                return Ok(index);
+            }
+            info!("Nearest-neighbour index {path} is out of date, rebuilding it...");
+        } else {
+            info!(
+                "Building nearest-neighbour index over {} centroids...",
+                log_model.centroids.nrows()
+            );
+        }
+        Self::build(log_model)
+    }
+
+    /// Saves the index to a file in JSON format, recording the centroids it is up to date with.
+    ///
+    /// The index is written to a uniquely named temporary file in the same directory first and
+    /// then renamed, so that concurrent saves never clobber each other's partial writes.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the index file.
+    /// * `centroids` - The centroids the index currently describes.
+    pub fn save(&mut self, path: &str, centroids: &Array2<f32>) -> Result<()> {
+        self.centroids_fingerprint = centroids_fingerprint(centroids);
+        let dir = Path::new(path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let mut temp_file = NamedTempFile::new_in(dir)?;
+        let mut writer = BufWriter::new(&mut temp_file);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        drop(writer);
+        temp_file.persist(path)?;
+        Ok(())
+    }
+
+    /// Returns the number of indexed centroids.
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    /// Returns whether the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    /// Adds the next row of the centroid matrix to the index.
+    ///
+    /// # Arguments
+    ///
+    /// * `centroids` - The centroids, whose row `len()` is the one to add.
+    pub fn insert(&mut self, centroids: &Array2<f32>) -> Result<()> {
+        let node = self.links.len();
+        if node >= centroids.nrows() {
+            return Err(E::msg(format!("No centroid {node} to add to the index")));
+        }
+        let level = self.random_level();
+        self.links.push(vec![Vec::new(); level + 1]);
+        match self.entry_point {
+            None => self.entry_point = Some(node),
+            Some(entry_point) => {
+                self.link(node, centroids)?;
+                if level >= self.links[entry_point].len() {
+                    self.entry_point = Some(node);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Relinks a centroid that has moved, so that it stays reachable from its new neighbourhood.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - The row of the moved centroid.
+    /// * `centroids` - The centroids, including the moved one.
+    pub fn update(&mut self, node: usize, centroids: &Array2<f32>) -> Result<()> {
+        if node >= self.links.len() {
+            return Err(E::msg(format!("Centroid {node} is not in the index")));
+        }
+        if self.links.len() > 1 {
+            self.link(node, centroids)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the two centroids closest to an embedding.
+    ///
+    /// Returns `None` if the index is empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `centroids` - The indexed centroids.
+    /// * `embedding` - The embedding of a log message.
+    pub fn nearest(
+        &self,
+        centroids: &Array2<f32>,
+        embedding: ArrayView1<f32>,
+    ) -> Result<Option<Nearest>> {
+        let Some(entry_point) = self.entry_point else {
+            return Ok(None);
+        };
+        let mut closest = self.candidate(centroids, embedding, entry_point)?;
+        for layer in (1..self.links[entry_point].len()).rev() {
+            closest = self.greedy_search(centroids, embedding, closest, layer)?;
+        }
+        let found = self.search_layer(
+            centroids,
+            embedding,
+            &[closest],
+            self.ef_search.max(2),
+            0,
+            None,
+        )?;
+        Ok(found.first().map(|best| Nearest {
+            index: best.node,
+            distance: best.distance,
+            second_distance: found.get(1).map(|second| second.distance),
+        }))
+    }
+
+    /// Connects a node to its nearest neighbours on each of its layers, and them back to it.
+    fn link(&mut self, node: usize, centroids: &Array2<f32>) -> Result<()> {
+        let entry_point = self
+            .entry_point
+            .expect("index with other nodes has an entry point");
+        let query = centroids.row(node);
+        let level = self.links[node].len() - 1;
+        let top = self.links[entry_point].len() - 1;
+
+        let mut closest = self.candidate(centroids, query, entry_point)?;
+        for layer in (level + 1..=top).rev() {
+            closest = self.greedy_search(centroids, query, closest, layer)?;
+        }
+        let mut entry_points = vec![closest];
+        for layer in (0..=level.min(top)).rev() {
+            let candidates = self.search_layer(
+                centroids,
+                query,
+                &entry_points,
+                self.ef_construction,
+                layer,
+                Some(node),
+            )?;
+            let neighbours =
+                self.select_neighbours(centroids, &candidates, self.max_links(layer))?;
+            for &neighbour in &neighbours {
+                let links = &mut self.links[neighbour][layer];
+                if !links.contains(&node) {
+                    links.push(node);
+                }
+                if links.len() > self.max_links(layer) {
+                    self.prune(neighbour, layer, centroids)?;
+                }
+            }
+            self.links[node][layer] = neighbours;
+            if !candidates.is_empty() {
+                entry_points = candidates;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reduces the links of a node on a layer to the allowed number.
+    fn prune(&mut self, node: usize, layer: usize, centroids: &Array2<f32>) -> Result<()> {
+        let query = centroids.row(node);
+        let mut candidates = self.links[node][layer]
+            .iter()
+            .map(|&other| self.candidate(centroids, query, other))
+            .collect::<Result<Vec<_>>>()?;
+        candidates.sort();
+        self.links[node][layer] =
+            self.select_neighbours(centroids, &candidates, self.max_links(layer))?;
+        Ok(())
+    }
+
+    /// Chooses up to `count` neighbours from candidates sorted by distance, skipping candidates
+    /// closer to an already chosen neighbour than to the query (the HNSW heuristic), which keeps
+    /// links spread out in all directions.
+    fn select_neighbours(
+        &self,
+        centroids: &Array2<f32>,
+        candidates: &[Candidate],
+        count: usize,
+    ) -> Result<Vec<usize>> {
+        let mut selected: Vec<usize> = Vec::with_capacity(count);
+        for candidate in candidates {
+            if selected.len() >= count {
+                break;
+            }
+            let mut keep = true;
+            for &chosen in &selected {
+                let distance = self
+                    .metric
+                    .distance(centroids.row(candidate.node), centroids.row(chosen))?;
+                if distance < candidate.distance {
+                    keep = false;
+                    break;
+                }
+            }
+            if keep {
+                selected.push(candidate.node);
+            }
+        }
+        Ok(selected)
+    }
+
+    /// Moves to ever closer neighbours on a layer until none is closer.
+    fn greedy_search(
+        &self,
+        centroids: &Array2<f32>,
+        query: ArrayView1<f32>,
+        mut closest: Candidate,
+        layer: usize,
+    ) -> Result<Candidate> {
+        loop {
+            let mut improved = false;
+            for &neighbour in &self.links[closest.node][layer] {
+                let candidate = self.candidate(centroids, query, neighbour)?;
+                if candidate < closest {
+                    closest = candidate;
+                    improved = true;
+                }
+            }
+            if !improved {
+                return Ok(closest);
+            }
+        }
+    }
+
+    /// Finds up to `ef` nodes close to the query on a layer, sorted by distance.
+    fn search_layer(
+        &self,
+        centroids: &Array2<f32>,
+        query: ArrayView1<f32>,
+        entry_points: &[Candidate],
+        ef: usize,
+        layer: usize,
+        exclude: Option<usize>,
+    ) -> Result<Vec<Candidate>> {
+        let mut visited: HashSet<usize> = entry_points.iter().map(|c| c.node).collect();
+        let mut to_visit: BinaryHeap<Reverse<Candidate>> =
+            entry_points.iter().copied().map(Reverse).collect();
+        let mut found: BinaryHeap<Candidate> = entry_points.iter().copied().collect();
+        while found.len() > ef {
+            found.pop();
+        }
+
+        while let Some(Reverse(current)) = to_visit.pop() {
+            if found.len() >= ef && found.peek().is_some_and(|worst| current > *worst) {
+                break;
+            }
+            for &neighbour in &self.links[current.node][layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let candidate = self.candidate(centroids, query, neighbour)?;
+                if found.len() < ef || found.peek().is_some_and(|worst| candidate < *worst) {
+                    to_visit.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        let mut found = found.into_sorted_vec();
+        found.retain(|candidate| Some(candidate.node) != exclude);
+        Ok(found)
+    }
+
+    /// Measures the distance from the query to a node.
+    fn candidate(
+        &self,
+        centroids: &Array2<f32>,
+        query: ArrayView1<f32>,
+        node: usize,
+    ) -> Result<Candidate> {
+        Ok(Candidate {
+            distance: self.metric.distance(query, centroids.row(node))?,
+            node,
+        })
+    }
+
+    /// The maximum number of links of a node on a layer.
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            2 * self.m
+        } else {
+            self.m
+        }
+    }
+
+    /// Draws the top layer of a new node from an exponentially decaying distribution, so that
+    /// each layer holds about `1/m` of the nodes of the layer below.
+    fn random_level(&mut self) -> usize {
+        let z = splitmix64(&mut self.rng_state);
+        let uniform = ((z >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        (-uniform.ln() / (self.m as f64).ln()) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::ModelIdentity;
+    use tempfile::tempdir;
+
+    /// Generates deterministic pseudo-random unit vectors.
+    fn random_vectors(count: usize, dimensions: usize, seed: u64) -> Array2<f32> {
+        let mut state = seed;
+        let mut vectors = Array2::from_shape_fn((count, dimensions), |_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+        });
+        for mut row in vectors.outer_iter_mut() {
+            DistanceMetric::Cosine.normalize(row.view_mut());
+        }
+        vectors
+    }
+
+    #[test]
+    fn test_index_finds_nearest_centroids() -> Result<()> {
+        let identity = ModelIdentity {
+            embedding_model: "test-model".to_string(),
+            embedding_fingerprint: fingerprint([b"weights".as_slice()]),
+            patterns_fingerprint: fingerprint([b"patterns".as_slice()]),
+        };
+        let mut log_model = LogModel::new(16, &identity, 0.5, 2, DistanceMetric::Euclidean);
+        for centroid in random_vectors(1500, 16, 1).outer_iter() {
+            log_model.add_cluster(centroid)?;
+        }
+        let dir = tempdir()?;
+        let path = dir.path().join("centroids.hnsw.json");
+        let path = path.to_str().unwrap();
+        let mut index = HnswIndex::open(path, &log_model)?;
+        index.save(path, &log_model.centroids)?;
+        let mut index = HnswIndex::open(path, &log_model)?;
+        assert_eq!(index.len(), 1500);
+
+        // Move one centroid onto a query and add another.
+        let queries = random_vectors(200, 16, 2);
+        log_model.centroids.row_mut(7).assign(&queries.row(0));
+        index.update(7, &log_model.centroids)?;
+        log_model.add_cluster(queries.row(1))?;
+        index.insert(&log_model.centroids)?;
+
+        let mut hits = 0;
+        for query in queries.outer_iter() {
+            let exact = log_model.nearest(query)?.unwrap();
+            let approximate = index.nearest(&log_model.centroids, query)?.unwrap();
+            if approximate.index == exact.index {
+                hits += 1;
+            }
+        }
+        assert!(hits >= 190, "recall too low: {hits} of 200");
+        assert_eq!(
+            index
+                .nearest(&log_model.centroids, queries.row(0))?
+                .unwrap()
+                .index,
+            7
+        );
+        assert_eq!(
+            index
+                .nearest(&log_model.centroids, queries.row(1))?
+                .unwrap()
+                .index,
+            1500
+        );
+
+        // The saved file no longer matches the moved centroids and is rebuilt.
+        assert_ne!(
+            centroids_fingerprint(&log_model.centroids),
+            index.centroids_fingerprint
+        );
+        Ok(())
+    }
+}
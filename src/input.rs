@@ -0,0 +1,337 @@
+use crate::model::fingerprint;
+use anyhow::{Error as E, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, Metadata};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// The input path that stands for standard input.
+pub const STDIN: &str = "-";
+
+/// Opens a log input for reading, treating `-` as standard input.
+///
+/// # Arguments
+///
+/// * `path` - The path to the log file, or `-` for standard input.
+pub fn open_input(path: &str) -> Result<Box<dyn BufRead>> {
+    if path == STDIN {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        let file = File::open(path).map_err(|e| E::msg(format!("Cannot open {path}: {e}")))?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Identifies a file independently of its path, so that a rotated file can be told apart
+/// from the new file that replaced it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileId {
+    /// The device the file resides on.
+    pub device: u64,
+    /// The inode number of the file.
+    pub inode: u64,
+}
+
+impl FileId {
+    /// Returns the identity of a file, or `None` on platforms without inode numbers.
+    ///
+    /// # Arguments
+    ///
+    /// * `metadata` - The metadata of the file.
+    #[cfg(unix)]
+    pub fn of(metadata: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(Self {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        })
+    }
+
+    /// Returns the identity of a file, or `None` on platforms without inode numbers.
+    ///
+    /// # Arguments
+    ///
+    /// * `metadata` - The metadata of the file.
+    #[cfg(not(unix))]
+    pub fn of(_metadata: &Metadata) -> Option<Self> {
+        None
+    }
+}
+
+/// How far a file has been read, used to resume reading in a later run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputPosition {
+    /// The identity of the file, if the platform provides one.
+    pub file_id: Option<FileId>,
+    /// The byte offset just past the last line read.
+    pub offset: u64,
+    /// The byte offset at which the last line read starts.
+    pub last_line_offset: u64,
+    /// The fingerprint of the last line read, without its line terminator.
+    pub last_line_hash: Option<String>,
+}
+
+/// Computes the fingerprint of a line as stored in an `InputPosition`.
+pub fn line_hash(line: &str) -> String {
+    fingerprint([line.as_bytes()])
+}
+
+/// The result of polling a followed input.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Poll {
+    /// A complete line, without its line terminator.
+    Line(String),
+    /// No new data arrived within the poll interval.
+    Idle,
+    /// The input has ended and no more data will arrive.
+    Closed,
+}
+
+/// The state of a followed regular file.
+struct FollowedFile {
+    path: PathBuf,
+    reader: BufReader<File>,
+    id: Option<FileId>,
+    position: u64,
+    partial: String,
+    pending: VecDeque<String>,
+    last_line_offset: u64,
+    last_line_hash: Option<String>,
+}
+
+/// The source of a followed input.
+enum Source {
+    File(FollowedFile),
+    Stdin(Receiver<io::Result<String>>),
+}
+
+/// Reads lines from an input as they are appended, like `tail -F`.
+///
+/// Regular files are polled for new data. Truncation restarts reading from the beginning, and a
+/// file that is renamed and recreated (as done by logrotate) is read to its end before switching
+/// to the new file. Standard input is read until it is closed.
+pub struct FollowReader {
+    source: Source,
+    poll_interval: Duration,
+}
+
+impl FollowReader {
+    /// Starts following an input, treating `-` as standard input.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the log file, or `-` for standard input.
+    /// * `poll_interval` - How long to wait for new data before reporting the input as idle.
+    pub fn open(path: &str, poll_interval: Duration) -> Result<Self> {
+        let source = if path == STDIN {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                for line in io::stdin().lock().lines() {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            });
+            Source::Stdin(receiver)
+        } else {
+            let file = File::open(path).map_err(|e| E::msg(format!("Cannot open {path}: {e}")))?;
+            let id = FileId::of(&file.metadata()?);
+            Source::File(FollowedFile {
+                path: PathBuf::from(path),
+                reader: BufReader::new(file),
+                id,
+                position: 0,
+                partial: String::new(),
+                pending: VecDeque::new(),
+                last_line_offset: 0,
+                last_line_hash: None,
+            })
+        };
+        Ok(Self {
+            source,
+            poll_interval,
+        })
+    }
+
+    /// Starts following a regular file from a position saved by an earlier run.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the log file.
+    /// * `position` - The position to resume from, already validated against the file.
+    /// * `poll_interval` - How long to wait for new data before reporting the input as idle.
+    pub fn resume(path: &str, position: &InputPosition, poll_interval: Duration) -> Result<Self> {
+        let mut reader = Self::open(path, poll_interval)?;
+        if let Source::File(file) = &mut reader.source {
+            file.position = file.reader.seek(SeekFrom::Start(position.offset))?;
+            file.last_line_offset = position.last_line_offset;
+            file.last_line_hash = position.last_line_hash.clone();
+        }
+        Ok(reader)
+    }
+
+    /// Returns how far the current file has been read, or `None` when following standard input.
+    pub fn position(&self) -> Option<InputPosition> {
+        match &self.source {
+            Source::File(file) => Some(InputPosition {
+                file_id: file.id,
+                offset: file.position - file.partial.len() as u64,
+                last_line_offset: file.last_line_offset,
+                last_line_hash: file.last_line_hash.clone(),
+            }),
+            Source::Stdin(_) => None,
+        }
+    }
+
+    /// Returns the incomplete last line read so far, treating it as complete.
+    ///
+    /// Used when the input is not followed and its end has been reached.
+    pub fn take_partial(&mut self) -> Option<String> {
+        match &mut self.source {
+            Source::File(file) if !file.partial.is_empty() => {
+                file.last_line_offset = file.position - file.partial.len() as u64;
+                let line = std::mem::take(&mut file.partial);
+                file.last_line_hash = Some(line_hash(&line));
+                Some(line)
+            }
+            _ => None,
+        }
+    }
+
+    /// Waits for the next line.
+    ///
+    /// Returns [`Poll::Idle`] if no complete line arrived within the poll interval, so that the
+    /// caller can do periodic work between lines.
+    pub fn poll_line(&mut self) -> Result<Poll> {
+        match &mut self.source {
+            Source::Stdin(receiver) => match receiver.recv_timeout(self.poll_interval) {
+                Ok(line) => Ok(Poll::Line(line?)),
+                Err(RecvTimeoutError::Timeout) => Ok(Poll::Idle),
+                Err(RecvTimeoutError::Disconnected) => Ok(Poll::Closed),
+            },
+            Source::File(file) => {
+                if let Some(line) = file.read_line()? {
+                    return Ok(Poll::Line(line));
+                }
+                file.check_rotation()?;
+                if let Some(line) = file.read_line()? {
+                    return Ok(Poll::Line(line));
+                }
+                thread::sleep(self.poll_interval);
+                Ok(match file.read_line()? {
+                    Some(line) => Poll::Line(line),
+                    None => Poll::Idle,
+                })
+            }
+        }
+    }
+}
+
+impl FollowedFile {
+    /// Reads the next complete line, keeping an incomplete last line for later.
+    fn read_line(&mut self) -> Result<Option<String>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Ok(Some(line));
+        }
+        let line_offset = self.position - self.partial.len() as u64;
+        let read = self.reader.read_line(&mut self.partial)?;
+        self.position += read as u64;
+        if !self.partial.ends_with('\n') {
+            return Ok(None);
+        }
+        let mut line = std::mem::take(&mut self.partial);
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        self.last_line_offset = line_offset;
+        self.last_line_hash = Some(line_hash(&line));
+        Ok(Some(line))
+    }
+
+    /// Handles truncation and replacement of the followed file once its end has been reached.
+    fn check_rotation(&mut self) -> Result<()> {
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // Rotated away and not recreated yet.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let id = FileId::of(&metadata);
+        if id != self.id {
+            info!(
+                "{} was replaced, switching to the new file.",
+                self.path.display()
+            );
+            // Pick up anything written to the old file after we last reached its end. Nothing
+            // more will be appended to it, so an incomplete last line is final.
+            self.reader.read_to_string(&mut self.partial)?;
+            let rest = std::mem::take(&mut self.partial);
+            self.pending.extend(rest.lines().map(str::to_string));
+
+            self.reader = BufReader::new(File::open(&self.path)?);
+            self.id = id;
+            self.position = 0;
+            self.last_line_offset = 0;
+            self.last_line_hash = None;
+            return Ok(());
+        }
+        if metadata.len() < self.position {
+            warn!(
+                "{} was truncated, reading from the beginning.",
+                self.path.display()
+            );
+            self.reader.seek(SeekFrom::Start(0))?;
+            self.position = 0;
+            self.partial.clear();
+            self.last_line_offset = 0;
+            self.last_line_hash = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_follow_handles_partial_lines_truncation_and_rotation() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("app.log");
+        let path_str = path.to_str().unwrap();
+        let mut file = File::create(&path)?;
+        write!(file, "first\nsec")?;
+
+        let line = |text: &str| Poll::Line(text.to_string());
+        let mut reader = FollowReader::open(path_str, Duration::from_millis(1))?;
+        assert_eq!(reader.poll_line()?, line("first"));
+        assert_eq!(reader.poll_line()?, Poll::Idle);
+        writeln!(file, "ond")?;
+        assert_eq!(reader.poll_line()?, line("second"));
+        let position = reader.position().unwrap();
+        assert_eq!((position.last_line_offset, position.offset), (6, 13));
+        assert_eq!(position.last_line_hash, Some(line_hash("second")));
+
+        let mut file = File::create(&path)?;
+        writeln!(file, "trunc")?;
+        assert_eq!(reader.poll_line()?, line("trunc"));
+
+        write!(file, "last\nunterminated")?;
+        std::fs::rename(&path, dir.path().join("app.log.1"))?;
+        let mut file = File::create(&path)?;
+        writeln!(file, "new file")?;
+        assert_eq!(reader.poll_line()?, line("last"));
+        assert_eq!(reader.poll_line()?, line("unterminated"));
+        assert_eq!(reader.poll_line()?, line("new file"));
+        assert_eq!(reader.poll_line()?, Poll::Idle);
+        Ok(())
+    }
+}
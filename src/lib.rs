@@ -1,3 +1,16 @@
+pub mod cache;
+pub mod checkpoint;
+pub mod clustering;
 pub mod commands;
 pub mod embedding;
+pub mod hashing;
+pub mod index;
+pub mod input;
+pub mod model;
+pub mod output;
+pub mod patterns;
 pub mod preprocessing;
+pub mod sampling;
+pub mod template;
+pub mod timestamp;
+pub mod tuning;
@@ -1,26 +1,164 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use railog::commands::{ingest, retrain, test_patterns, train};
-use railog::preprocessing::LogPreprocessor;
+use anyhow::{Error as E, Result};
+use clap::{Args, Parser, Subcommand};
+use log::info;
+use railog::cache::{DEFAULT_CACHE_FILE, DEFAULT_CACHE_MAX_BYTES};
+use railog::checkpoint::default_checkpoint_file;
+use railog::clustering::ClusteringAlgorithm;
+use railog::commands::{
+    classify, ingest, label, list_builtin_patterns, list_clusters, retrain, test_patterns, train,
+    tune, ClassifyOptions, FollowOptions, IngestOptions, RetrainOptions, TrainOptions, TuneOptions,
+};
+use railog::embedding::{EmbedderBackend, EmbeddingConfig, ModelSource};
+use railog::index::default_index_file;
+use railog::model::{DistanceMetric, MatchThreshold};
+use railog::output::OutputFormat;
+use railog::patterns::ALL_BUILTIN;
+use railog::preprocessing::{LogPreprocessor, DEFAULT_PATTERNS_FILE};
+use railog::timestamp::{TimeZoneSpec, TimestampExtractor, TimestampFormat};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
-    /// Path to the regex patterns file
-    #[arg(short, long, global = true, default_value = "patterns.txt")]
-    patterns_file: String,
+    /// Path to the regex patterns file, structured if it ends in .toml [default: patterns.txt, or
+    /// all built-in patterns if it does not exist]
+    #[arg(short, long, global = true)]
+    patterns_file: Option<String>,
+    /// Built-in patterns to apply after the patterns file, by name or group (see
+    /// `test-patterns --list-builtin`), or "all"
+    #[arg(long, global = true, value_delimiter = ',')]
+    builtin_patterns: Vec<String>,
+    /// Load the embedding model from a local directory containing config.json,
+    /// tokenizer.json and model.safetensors instead of the Hugging Face Hub
+    #[arg(long, global = true)]
+    model_dir: Option<PathBuf>,
+    /// Never access the network; load the embedding model from --model-dir or the local Hugging Face cache
+    #[arg(long, global = true)]
+    offline: bool,
+    /// Maximum number of tokens embedded per log line; longer lines are truncated.
+    /// Defaults to the limit of the embedding model
+    #[arg(long, global = true)]
+    max_length: Option<usize>,
+    /// Embedding backend: "minilm" (sentence transformer) or "hashing" (lightweight token
+    /// hashing that needs no model files)
+    #[arg(long, global = true, default_value = "minilm")]
+    embedder: EmbedderBackend,
+    /// Number of dimensions of the hashing embedder
+    #[arg(long, global = true)]
+    dimensions: Option<usize>,
+    /// File caching the embeddings of preprocessed log lines across runs
+    #[arg(long, global = true, default_value = DEFAULT_CACHE_FILE)]
+    embedding_cache: PathBuf,
+    /// Maximum size of the embedding cache in megabytes; the least recently used entries are evicted
+    #[arg(long, global = true, default_value_t = DEFAULT_CACHE_MAX_BYTES / (1024 * 1024))]
+    embedding_cache_size: u64,
+    /// Do not read or write the embedding cache
+    #[arg(long, global = true)]
+    no_embedding_cache: bool,
     /// Enable verbose output
     #[arg(short, long, global = true)]
     verbose: bool,
 }
 
+/// Options controlling how timestamps are extracted from log lines.
+#[derive(Args)]
+struct TimestampArgs {
+    /// Timestamp formats to try, in order (syslog, rfc5424, rfc3339, epoch, epoch-millis, apache).
+    /// Defaults to all of them.
+    #[arg(long = "timestamp-format", value_delimiter = ',')]
+    formats: Vec<TimestampFormat>,
+    /// Regex locating a custom timestamp; its first capture group is parsed with --timestamp-strftime
+    #[arg(long, requires = "timestamp_strftime")]
+    timestamp_regex: Option<String>,
+    /// strftime format of the custom timestamp captured by --timestamp-regex
+    #[arg(long, requires = "timestamp_regex")]
+    timestamp_strftime: Option<String>,
+    /// Time zone for timestamps without a UTC offset: "local", "UTC", an offset like "+01:00"
+    /// or an IANA name like "Europe/Berlin"
+    #[arg(long, default_value = "local")]
+    timezone: TimeZoneSpec,
+}
+
+impl TimestampArgs {
+    /// Builds the timestamp extractor described by the options.
+    fn extractor(&self) -> Result<TimestampExtractor> {
+        let mut formats = Vec::new();
+        if let (Some(regex), Some(strftime)) = (&self.timestamp_regex, &self.timestamp_strftime) {
+            formats.push(TimestampFormat::custom(regex, strftime)?);
+        }
+        if self.formats.is_empty() {
+            formats.extend(TimestampFormat::builtin());
+        } else {
+            formats.extend(self.formats.iter().cloned());
+        }
+        Ok(TimestampExtractor::new(formats, self.timezone))
+    }
+}
+
+/// Options controlling the approximate nearest-neighbour index over the centroids.
+#[derive(Args)]
+struct IndexArgs {
+    /// Search the centroids with an approximate nearest-neighbour (HNSW) index, building it if
+    /// needed. Once the index file exists, it is used and kept up to date without this flag
+    #[arg(long)]
+    ann: bool,
+    /// Compare every line with all centroids instead of using the index, e.g. to verify results
+    #[arg(long, conflicts_with = "ann")]
+    exact: bool,
+    /// Path to the index file [default: <centroids-file> with extension .hnsw.json]
+    #[arg(long)]
+    index_file: Option<String>,
+}
+
+impl IndexArgs {
+    /// Returns the index file to use with a centroids file, or `None` for exact search.
+    fn index_file(&self, centroids_file: &str) -> Option<String> {
+        if self.exact {
+            return None;
+        }
+        let index_file = self
+            .index_file
+            .clone()
+            .unwrap_or_else(|| default_index_file(centroids_file));
+        (self.ann || Path::new(&index_file).exists()).then_some(index_file)
+    }
+}
+
+/// Options controlling the distance below which a line matches a cluster.
+#[derive(Args)]
+struct ThresholdArgs {
+    /// Match every cluster within this distance instead of using the per-cluster radii
+    #[arg(short, long)]
+    threshold: Option<f64>,
+    /// Factor applied to the radius of each cluster to get its match threshold
+    #[arg(long, default_value_t = 1.5)]
+    radius_multiplier: f64,
+    /// Threshold for clusters without a radius, e.g. those formed from a single message
+    #[arg(long, default_value_t = 0.5)]
+    fallback_threshold: f64,
+}
+
+impl ThresholdArgs {
+    /// Returns the global threshold if one was given, or the scaled per-cluster radii otherwise.
+    fn match_threshold(&self) -> MatchThreshold {
+        match self.threshold {
+            Some(threshold) => MatchThreshold::Global(threshold),
+            None => MatchThreshold::Radius {
+                multiplier: self.radius_multiplier,
+                fallback: self.fallback_threshold,
+            },
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Train the model on a log file to create initial centroids
     Train {
-        /// Path to the log file to train on
+        /// Path to the log file to train on, or "-" for standard input
         #[arg(short, long, default_value = "example.txt")]
         input_file: String,
         /// Path to save the centroids to
@@ -32,10 +170,35 @@ enum Commands {
         /// The minimum number of points required to form a dense region (a cluster).
         #[arg(short, long, default_value_t = 3)]
         min_points: usize,
+        /// Distance metric for clustering and matching: "euclidean" or "cosine". Recorded in the model
+        #[arg(long, default_value = "euclidean")]
+        metric: DistanceMetric,
+        /// Train on a uniform random sample of this many lines instead of the whole input
+        #[arg(short, long)]
+        sample_size: Option<usize>,
+        /// Clustering algorithm: "dbscan", "hdbscan" (variable density, ignores epsilon) or
+        /// "leader" (single-pass online clustering within epsilon)
+        #[arg(short, long, default_value = "dbscan")]
+        algorithm: ClusteringAlgorithm,
+    },
+    /// Suggest epsilon and min points for train from a sample of a log file
+    Tune {
+        /// Path to the log file to analyse, or "-" for standard input
+        #[arg(short, long, default_value = "example.txt")]
+        input_file: String,
+        /// Maximum number of randomly sampled log lines to embed and analyse
+        #[arg(short, long, default_value_t = 2000)]
+        sample_size: usize,
+        /// Comma-separated DBSCAN min points values to evaluate
+        #[arg(short, long, value_delimiter = ',', default_value = "3,5,10")]
+        min_points: Vec<usize>,
+        /// Distance metric the model will be trained with: "euclidean" or "cosine"
+        #[arg(long, default_value = "euclidean")]
+        metric: DistanceMetric,
     },
     /// Ingest a file of new logs, updating centroids for matches and logging non-matches
     Ingest {
-        /// Path to the file with new log messages
+        /// Path to the file with new log messages, or "-" for standard input
         #[arg(short, long, default_value = "new_logs.txt")]
         input_file: String,
         /// Path to the centroids file
@@ -44,30 +207,128 @@ enum Commands {
         /// Path for saving unmatched logs
         #[arg(short, long, default_value = "unmatched.log")]
         unmatched_file: String,
-        /// Distance threshold for matching a cluster.
-        #[arg(short, long, default_value_t = 0.5)]
-        threshold: f64,
-        /// Learning rate for updating centroids on a match.
+        #[command(flatten)]
+        threshold: ThresholdArgs,
+        /// Learning rate for updating centroids and cluster radii on a match.
         #[arg(short, long, default_value_t = 0.1)]
         learning_rate: f64,
+        /// Continue with a warning if the model was built with a different embedding model or patterns
+        #[arg(long)]
+        allow_mismatch: bool,
+        #[command(flatten)]
+        timestamps: TimestampArgs,
+        /// Keep reading the input as it grows (handling truncation and rotation) until stopped
+        #[arg(short, long)]
+        follow: bool,
+        /// Seconds to wait for new data before flushing unmatched logs when following
+        #[arg(long, default_value = "1", value_parser = parse_seconds, requires = "follow")]
+        poll_interval: Duration,
+        /// Seconds between saves of the updated centroids when following
+        #[arg(long, default_value = "60", value_parser = parse_seconds, requires = "follow")]
+        checkpoint_interval: Duration,
+        /// File recording how far each input has been ingested [default: <centroids-file> with
+        /// extension .checkpoint.json]
+        #[arg(long)]
+        checkpoint_file: Option<String>,
+        /// Ignore the saved checkpoint and read the input from the beginning
+        #[arg(long)]
+        from_start: bool,
+        /// Write a result for every line to standard output: "text" or "jsonl"
+        #[arg(long)]
+        output_format: Option<OutputFormat>,
+        /// Number of log lines to embed together in one pass of the model
+        #[arg(long, default_value_t = 256)]
+        batch_size: usize,
+        #[command(flatten)]
+        index: IndexArgs,
     },
     /// Retrain the model by creating new centroids from a log file
     Retrain {
-        /// Path to the log file to create new centroids from
+        /// Path to the log file to create new centroids from, or "-" for standard input
         #[arg(short, long, default_value = "unmatched.log")]
         input_file: String,
         /// Path to the centroids file to update
         #[arg(short, long, default_value = "centroids.json")]
         centroids_file: String,
+        #[command(flatten)]
+        threshold: ThresholdArgs,
+        /// Epsilon for grouping new lines with the algorithm used by train. Defaults to the value
+        /// used by train; required if train recorded none, except for HDBSCAN
+        #[arg(short, long)]
+        epsilon: Option<f32>,
+        /// Min points for grouping new lines. Defaults to the value used by train
+        #[arg(short, long)]
+        min_points: Option<usize>,
+        /// Add a centroid for every message left as noise, even if it stands for fewer than min
+        /// points lines
+        #[arg(long)]
+        keep_noise: bool,
+        /// Continue with a warning if the model was built with a different embedding model or patterns
+        #[arg(long)]
+        allow_mismatch: bool,
+        #[command(flatten)]
+        index: IndexArgs,
+    },
+    /// Classify log lines against the model without modifying it
+    Classify {
+        /// Path to the log file to classify, or "-" for standard input
+        #[arg(short, long, default_value = "new_logs.txt")]
+        input_file: String,
+        /// Path to the centroids file
+        #[arg(short, long, default_value = "centroids.json")]
+        centroids_file: String,
+        #[command(flatten)]
+        threshold: ThresholdArgs,
+        /// Continue with a warning if the model was built with a different embedding model or patterns
+        #[arg(long)]
+        allow_mismatch: bool,
+        /// Exit with an error if any line does not match a cluster
+        #[arg(long)]
+        fail_on_unmatched: bool,
+        #[command(flatten)]
+        timestamps: TimestampArgs,
+        /// Format of the result written for every line: "text" or "jsonl"
+        #[arg(long, default_value = "text")]
+        output_format: OutputFormat,
+        #[command(flatten)]
+        index: IndexArgs,
+    },
+    /// Set or clear the human-readable label of a cluster
+    Label {
+        /// Path to the centroids file to update
+        #[arg(short, long, default_value = "centroids.json")]
+        centroids_file: String,
+        /// The ID of the cluster to label
+        #[arg(short = 'i', long)]
+        cluster_id: u64,
+        /// The new label; omit to remove the existing label
+        #[arg(short, long)]
+        label: Option<String>,
+    },
+    /// List the clusters of a model with their statistics
+    Clusters {
+        /// Path to the centroids file
+        #[arg(short, long, default_value = "centroids.json")]
+        centroids_file: String,
     },
     /// Test the regex patterns on a log file
     TestPatterns {
-        /// Path to the log file to test patterns on
+        /// Path to the log file to test patterns on, or "-" for standard input
         #[arg(short, long, default_value = "new_logs.txt")]
         input_file: String,
+        /// List the built-in patterns instead of testing patterns
+        #[arg(long)]
+        list_builtin: bool,
     },
 }
 
+/// Parses a non-negative, finite number of seconds into a `Duration`.
+fn parse_seconds(value: &str) -> Result<Duration> {
+    let seconds: f64 = value.parse()?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|_| E::msg(format!("'{value}' is not a non-negative number of seconds")))
+}
+
 /// The main entry point for the application.
 ///
 /// This function parses the command-line arguments and calls the appropriate subcommand.
@@ -82,48 +343,201 @@ fn main() -> Result<()> {
         })
         .init();
 
-    let preprocessor = LogPreprocessor::new(&cli.patterns_file)?;
+    let patterns_file = cli.patterns_file.clone().or_else(|| {
+        Path::new(DEFAULT_PATTERNS_FILE)
+            .exists()
+            .then(|| DEFAULT_PATTERNS_FILE.to_string())
+    });
+    let mut preprocessor = match &patterns_file {
+        Some(patterns_file) => LogPreprocessor::new(patterns_file)?,
+        None if cli.builtin_patterns.is_empty() => {
+            info!("No {DEFAULT_PATTERNS_FILE} found, using all built-in patterns.");
+            LogPreprocessor::builtin(&[ALL_BUILTIN])?
+        }
+        None => LogPreprocessor::default(),
+    };
+    preprocessor.add_builtin(&cli.builtin_patterns)?;
+    let model_source = match (&cli.model_dir, cli.offline) {
+        (Some(dir), _) => ModelSource::Dir(dir.clone()),
+        (None, true) => ModelSource::HubOffline,
+        (None, false) => ModelSource::Hub,
+    };
+    let embedding_config = EmbeddingConfig {
+        backend: cli.embedder,
+        source: model_source,
+        max_length: cli.max_length,
+        dimensions: cli.dimensions,
+        cache_file: (!cli.no_embedding_cache).then(|| cli.embedding_cache.clone()),
+        cache_max_bytes: Some(cli.embedding_cache_size * 1024 * 1024),
+    };
     match &cli.command {
         Commands::Train {
             input_file,
             output_file,
             epsilon,
             min_points,
+            metric,
+            sample_size,
+            algorithm,
         } => {
+            let options = TrainOptions {
+                epsilon: *epsilon,
+                min_points: *min_points,
+                metric: *metric,
+                sample_size: *sample_size,
+                algorithm: *algorithm,
+            };
             train(
                 input_file,
                 output_file,
-                *epsilon,
-                *min_points,
+                &options,
                 &preprocessor,
+                &mut embedding_config.load()?,
                 cli.verbose,
             )?;
         }
+        Commands::Tune {
+            input_file,
+            sample_size,
+            min_points,
+            metric,
+        } => {
+            let options = TuneOptions {
+                sample_size: *sample_size,
+                min_points: min_points.clone(),
+                metric: *metric,
+            };
+            tune(
+                input_file,
+                &options,
+                &preprocessor,
+                &mut embedding_config.load()?,
+            )?;
+        }
         Commands::Ingest {
             input_file,
             centroids_file,
             unmatched_file,
             threshold,
             learning_rate,
+            allow_mismatch,
+            timestamps,
+            follow,
+            poll_interval,
+            checkpoint_interval,
+            checkpoint_file,
+            from_start,
+            output_format,
+            batch_size,
+            index,
         } => {
+            let options = IngestOptions {
+                threshold: threshold.match_threshold(),
+                learning_rate: *learning_rate,
+                allow_mismatch: *allow_mismatch,
+                timestamps: timestamps.extractor()?,
+                follow: follow.then(|| FollowOptions {
+                    poll_interval: *poll_interval,
+                    checkpoint_interval: *checkpoint_interval,
+                }),
+                checkpoint_file: Some(
+                    checkpoint_file
+                        .clone()
+                        .unwrap_or_else(|| default_checkpoint_file(centroids_file)),
+                ),
+                from_start: *from_start,
+                output_format: *output_format,
+                batch_size: *batch_size,
+                index_file: index.index_file(centroids_file),
+            };
             ingest(
                 input_file,
                 centroids_file,
                 unmatched_file,
-                *threshold,
-                *learning_rate,
+                options,
                 &preprocessor,
+                &mut embedding_config.load()?,
                 cli.verbose,
             )?;
         }
         Commands::Retrain {
             input_file,
             centroids_file,
+            threshold,
+            epsilon,
+            min_points,
+            keep_noise,
+            allow_mismatch,
+            index,
+        } => {
+            let options = RetrainOptions {
+                threshold: threshold.match_threshold(),
+                epsilon: *epsilon,
+                min_points: *min_points,
+                keep_noise: *keep_noise,
+                allow_mismatch: *allow_mismatch,
+                index_file: index.index_file(centroids_file),
+            };
+            retrain(
+                input_file,
+                centroids_file,
+                &options,
+                &preprocessor,
+                &mut embedding_config.load()?,
+                cli.verbose,
+            )?;
+        }
+        Commands::Classify {
+            input_file,
+            centroids_file,
+            threshold,
+            allow_mismatch,
+            fail_on_unmatched,
+            timestamps,
+            output_format,
+            index,
+        } => {
+            let options = ClassifyOptions {
+                threshold: threshold.match_threshold(),
+                allow_mismatch: *allow_mismatch,
+                timestamps: timestamps.extractor()?,
+                output_format: *output_format,
+                index_file: index.index_file(centroids_file),
+            };
+            let summary = classify(
+                input_file,
+                centroids_file,
+                options,
+                &preprocessor,
+                &mut embedding_config.load()?,
+            )?;
+            if *fail_on_unmatched && summary.matched < summary.total {
+                return Err(E::msg(format!(
+                    "{} of {} lines did not match any cluster",
+                    summary.total - summary.matched,
+                    summary.total
+                )));
+            }
+        }
+        Commands::Label {
+            centroids_file,
+            cluster_id,
+            label: new_label,
         } => {
-            retrain(input_file, centroids_file, &preprocessor, cli.verbose)?;
+            label(centroids_file, *cluster_id, new_label.as_deref())?;
+        }
+        Commands::Clusters { centroids_file } => {
+            list_clusters(centroids_file)?;
         }
-        Commands::TestPatterns { input_file } => {
-            test_patterns(input_file, &preprocessor)?;
+        Commands::TestPatterns {
+            input_file,
+            list_builtin,
+        } => {
+            if *list_builtin {
+                list_builtin_patterns();
+            } else {
+                test_patterns(input_file, &preprocessor)?;
+            }
         }
     }
     Ok(())
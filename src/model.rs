@@ -0,0 +1,879 @@
+use crate::clustering::ClusteringAlgorithm;
+use crate::embedding::Embedder;
+use crate::preprocessing::LogPreprocessor;
+use anyhow::{Error as E, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use ndarray::{Array2, ArrayView1, ArrayViewMut1, Axis};
+use ndarray_stats::DeviationExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The current version of the model file format.
+///
+/// Version 0 is the legacy format, a bare serialized centroid matrix without any metadata.
+/// Version 1 added the metadata, version 2 added per-cluster information, version 3 added the
+/// distance metric, version 4 added the clustering algorithm, version 5 added per-cluster radii,
+/// version 6 added per-cluster templates, version 7 added per-cluster parameter values.
+pub const MODEL_FORMAT_VERSION: u32 = 7;
+
+/// The maximum number of exemplar log lines kept per cluster.
+pub const MAX_EXEMPLARS: usize = 5;
+
+/// The maximum number of distinct values counted per parameter of a cluster.
+pub const MAX_PARAM_VALUES: usize = 20;
+
+/// The fraction of a cluster's lines that lie within its radius.
+pub const RADIUS_QUANTILE: f64 = 0.95;
+
+/// Computes the radius of a cluster: the distance from the centroid within which
+/// `RADIUS_QUANTILE` of its lines lie.
+///
+/// Returns `None` if the radius is zero up to rounding, e.g. for a cluster of a single distinct
+/// message, since it says nothing about how far the cluster's messages spread.
+///
+/// # Arguments
+///
+/// * `distances` - The distance of each member message to the centroid, with its number of lines.
+pub fn cluster_radius(distances: &[(f64, u64)]) -> Option<f64> {
+    let mut distances = distances.to_vec();
+    distances.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total: u64 = distances.iter().map(|&(_, count)| count).sum();
+    let target = (RADIUS_QUANTILE * total as f64).ceil() as u64;
+    let mut seen = 0;
+    let radius = distances.iter().find_map(|&(distance, count)| {
+        seen += count;
+        (seen >= target).then_some(distance)
+    })?;
+    (radius > 1e-6).then_some(radius)
+}
+
+/// Computes a hex-encoded SHA-256 fingerprint over a sequence of byte strings.
+///
+/// Each part is length-prefixed so that different splits of the same bytes hash differently.
+pub fn fingerprint<'a, I>(parts: I) -> String
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut fingerprinter = Fingerprinter::default();
+    for part in parts {
+        fingerprinter.add(part);
+    }
+    fingerprinter.finish()
+}
+
+/// Builds the same fingerprint as `fingerprint` part by part, so that large files can be
+/// streamed through the hash instead of being read into memory.
+#[derive(Default)]
+pub struct Fingerprinter {
+    hasher: Sha256,
+}
+
+impl Fingerprinter {
+    /// Adds a part to the fingerprint.
+    ///
+    /// # Arguments
+    ///
+    /// * `part` - The bytes of the part.
+    pub fn add(&mut self, part: &[u8]) {
+        self.hasher.update((part.len() as u64).to_le_bytes());
+        self.hasher.update(part);
+    }
+
+    /// Adds the contents of a file as one part, reading it in chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the file.
+    pub fn add_file(&mut self, path: &Path) -> Result<()> {
+        let mut file = File::open(path)?;
+        let length = file.metadata()?.len();
+        self.hasher.update(length.to_le_bytes());
+        let copied = std::io::copy(&mut file, &mut self.hasher)?;
+        if copied != length {
+            return Err(E::msg(format!(
+                "{} changed while it was being read",
+                path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the hex-encoded fingerprint.
+    pub fn finish(self) -> String {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+/// How the distance between an embedding and a centroid is measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceMetric {
+    /// The Euclidean (L2) distance.
+    #[default]
+    Euclidean,
+    /// One minus the cosine similarity, ranging from 0 (same direction) to 2 (opposite).
+    ///
+    /// Centroids are kept on the unit sphere, like the embeddings themselves.
+    Cosine,
+}
+
+impl DistanceMetric {
+    /// Returns the distance between two vectors.
+    ///
+    /// # Arguments
+    ///
+    /// * `a` - The first vector.
+    /// * `b` - The second vector, of the same length.
+    pub fn distance(&self, a: ArrayView1<f32>, b: ArrayView1<f32>) -> Result<f64> {
+        match self {
+            Self::Euclidean => Ok(a.l2_dist(&b)?),
+            Self::Cosine => {
+                if a.len() != b.len() {
+                    return Err(E::msg(format!(
+                        "Cannot compare vectors of length {} and {}",
+                        a.len(),
+                        b.len()
+                    )));
+                }
+                let norms = (a.dot(&a) as f64).sqrt() * (b.dot(&b) as f64).sqrt();
+                if norms == 0.0 {
+                    return Ok(1.0);
+                }
+                Ok(1.0 - a.dot(&b) as f64 / norms)
+            }
+        }
+    }
+
+    /// Brings a centroid back onto the unit sphere if the metric requires it.
+    ///
+    /// # Arguments
+    ///
+    /// * `centroid` - The centroid to normalize in place.
+    pub fn normalize(&self, mut centroid: ArrayViewMut1<f32>) {
+        if *self == Self::Cosine {
+            let norm = centroid.dot(&centroid).sqrt();
+            if norm > 0.0 {
+                centroid /= norm;
+            }
+        }
+    }
+}
+
+impl FromStr for DistanceMetric {
+    type Err = E;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "euclidean" | "l2" => Ok(Self::Euclidean),
+            "cosine" => Ok(Self::Cosine),
+            _ => Err(E::msg(format!(
+                "Unknown distance metric '{name}', expected euclidean or cosine"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for DistanceMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Euclidean => "euclidean",
+            Self::Cosine => "cosine",
+        })
+    }
+}
+
+/// Describes how a model was built.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelMetadata {
+    /// The name of the embedding model used to produce the centroids.
+    pub embedding_model: Option<String>,
+    /// The fingerprint of the embedding model files.
+    pub embedding_fingerprint: Option<String>,
+    /// The fingerprint of the preprocessing patterns.
+    pub patterns_fingerprint: Option<String>,
+    /// The clustering algorithm used for training.
+    #[serde(default)]
+    pub algorithm: ClusteringAlgorithm,
+    /// The epsilon used for training, if the algorithm has one.
+    pub epsilon: Option<f32>,
+    /// The minimum number of points used for training.
+    pub min_points: Option<usize>,
+    /// The distance metric used for clustering and matching.
+    #[serde(default)]
+    pub metric: DistanceMetric,
+    /// The dimensionality of the embeddings.
+    pub dimensions: usize,
+    /// When the model was first created.
+    pub created_at: DateTime<Utc>,
+    /// When the model was last updated by `train`, `ingest` or `retrain`.
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Information about a single cluster (log pattern).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cluster {
+    /// A stable identifier that never changes or gets reused once assigned.
+    pub id: u64,
+    /// An optional human-readable label.
+    pub label: Option<String>,
+    /// The pattern shared by the cluster's messages, with variable parts replaced by `<*>`.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// The number of log lines assigned to this cluster.
+    pub hits: u64,
+    /// The distance from the centroid within which most of the cluster's lines lie, or `None`
+    /// if it is unknown.
+    #[serde(default)]
+    pub radius: Option<f64>,
+    /// When a log line was first assigned to this cluster.
+    pub first_seen: Option<DateTime<Utc>>,
+    /// When a log line was last assigned to this cluster.
+    pub last_seen: Option<DateTime<Utc>>,
+    /// A few raw log lines belonging to this cluster.
+    pub exemplars: Vec<String>,
+    /// The values of the named parameters extracted from the lines assigned to this cluster.
+    #[serde(default)]
+    pub params: BTreeMap<String, ParamValues>,
+}
+
+/// The values of a named parameter seen in the lines of a cluster.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamValues {
+    /// How often each value was seen, for up to `MAX_PARAM_VALUES` distinct values.
+    pub counts: BTreeMap<String, u64>,
+    /// How often any other value was seen.
+    pub other: u64,
+}
+
+impl ParamValues {
+    /// Returns the most frequent values with their counts, most frequent first.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - The maximum number of values to return.
+    pub fn top(&self, count: usize) -> Vec<(&str, u64)> {
+        let mut values: Vec<(&str, u64)> = self
+            .counts
+            .iter()
+            .map(|(value, &seen)| (value.as_str(), seen))
+            .collect();
+        values.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        values.truncate(count);
+        values
+    }
+}
+
+impl Cluster {
+    /// Records that a log line was assigned to this cluster.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The raw log line.
+    /// * `seen` - When the log line was produced.
+    pub fn record_hit(&mut self, line: &str, seen: DateTime<Utc>) {
+        self.hits += 1;
+        if self.first_seen.is_none_or(|first| seen < first) {
+            self.first_seen = Some(seen);
+        }
+        if self.last_seen.is_none_or(|last| seen > last) {
+            self.last_seen = Some(seen);
+        }
+        if self.exemplars.len() < MAX_EXEMPLARS && !self.exemplars.iter().any(|e| e == line) {
+            self.exemplars.push(line.to_string());
+        }
+    }
+
+    /// Counts the parameter values extracted from a log line assigned to this cluster.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameter names and values, as returned by
+    ///   `LogPreprocessor::preprocess_with_params`.
+    pub fn record_params(&mut self, params: &[(String, String)]) {
+        for (name, value) in params {
+            let values = self.params.entry(name.clone()).or_default();
+            if let Some(seen) = values.counts.get_mut(value) {
+                *seen += 1;
+            } else if values.counts.len() < MAX_PARAM_VALUES {
+                values.counts.insert(value.clone(), 1);
+            } else {
+                values.other += 1;
+            }
+        }
+    }
+
+    /// Adjusts the radius towards the `RADIUS_QUANTILE` of the distances of matched lines,
+    /// growing it a little when a line falls outside and shrinking it a little when one falls
+    /// inside. A cluster without a radius keeps none.
+    ///
+    /// # Arguments
+    ///
+    /// * `distance` - The distance of the matched line to the centroid.
+    /// * `learning_rate` - The relative size of each adjustment.
+    pub fn update_radius(&mut self, distance: f64, learning_rate: f64) {
+        if let Some(radius) = &mut self.radius {
+            let step = if distance > *radius {
+                RADIUS_QUANTILE
+            } else {
+                RADIUS_QUANTILE - 1.0
+            };
+            *radius *= 1.0 + learning_rate * step;
+        }
+    }
+
+    /// Returns the ID followed by the label and the template, if known.
+    pub fn display_name(&self) -> String {
+        cluster_name(self.id, self.label.as_deref(), self.template.as_deref())
+    }
+}
+
+/// Formats the name of a cluster as shown to users, e.g. `17 (ssh) [session opened for <*>]`.
+///
+/// # Arguments
+///
+/// * `id` - The stable cluster ID.
+/// * `label` - The label of the cluster, if any.
+/// * `template` - The template of the cluster, if known.
+pub fn cluster_name(id: u64, label: Option<&str>, template: Option<&str>) -> String {
+    let mut name = id.to_string();
+    if let Some(label) = label {
+        name.push_str(&format!(" ({label})"));
+    }
+    if let Some(template) = template {
+        name.push_str(&format!(" [{template}]"));
+    }
+    name
+}
+
+/// How the distance threshold for matching a cluster is chosen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchThreshold {
+    /// The same threshold for every cluster.
+    Global(f64),
+    /// The radius of each cluster scaled by a multiplier, so that tight clusters match strictly
+    /// and wide ones loosely.
+    Radius {
+        /// The factor applied to each radius.
+        multiplier: f64,
+        /// The threshold for clusters without a radius.
+        fallback: f64,
+    },
+}
+
+impl MatchThreshold {
+    /// Returns the distance below which a line matches a cluster.
+    ///
+    /// # Arguments
+    ///
+    /// * `cluster` - The cluster to match.
+    pub fn for_cluster(&self, cluster: &Cluster) -> f64 {
+        match *self {
+            Self::Global(threshold) => threshold,
+            Self::Radius {
+                multiplier,
+                fallback,
+            } => cluster
+                .radius
+                .map_or(fallback, |radius| radius * multiplier),
+        }
+    }
+}
+
+/// The clusters closest to an embedding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Nearest {
+    /// The row index of the closest centroid.
+    pub index: usize,
+    /// The distance to the closest centroid.
+    pub distance: f64,
+    /// The distance to the second-closest centroid, if the model has more than one cluster.
+    pub second_distance: Option<f64>,
+}
+
+/// A trained model: the cluster centroids and the metadata describing how they were produced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogModel {
+    /// The model file format version.
+    pub version: u32,
+    /// Information about how the model was built.
+    pub metadata: ModelMetadata,
+    /// The cluster centroids, one per row.
+    pub centroids: Array2<f32>,
+    /// Information about each cluster, in the same order as the rows of `centroids`.
+    #[serde(default)]
+    pub clusters: Vec<Cluster>,
+    /// The ID to assign to the next new cluster.
+    #[serde(default)]
+    pub next_cluster_id: u64,
+}
+
+/// Identifies the embedding model and preprocessing patterns currently in use.
+#[derive(Debug, Clone)]
+pub struct ModelIdentity {
+    /// The name of the embedding model.
+    pub embedding_model: String,
+    /// The fingerprint of the embedding model files.
+    pub embedding_fingerprint: String,
+    /// The fingerprint of the preprocessing patterns.
+    pub patterns_fingerprint: String,
+}
+
+impl ModelIdentity {
+    /// Describes the given embedder and preprocessor.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The loaded embedder.
+    /// * `preprocessor` - The `LogPreprocessor` applied to log messages.
+    pub fn new<M: Embedder + ?Sized>(model: &M, preprocessor: &LogPreprocessor) -> Self {
+        Self {
+            embedding_model: model.name().to_string(),
+            embedding_fingerprint: model.fingerprint().to_string(),
+            patterns_fingerprint: preprocessor.fingerprint(),
+        }
+    }
+}
+
+impl LogModel {
+    /// Creates a new model without any clusters.
+    ///
+    /// # Arguments
+    ///
+    /// * `dimensions` - The dimensionality of the embeddings.
+    /// * `identity` - The embedding model and patterns used to produce the centroids.
+    /// * `epsilon` - The DBSCAN epsilon used for training.
+    /// * `min_points` - The DBSCAN minimum number of points used for training.
+    /// * `metric` - The distance metric used for clustering and matching.
+    pub fn new(
+        dimensions: usize,
+        identity: &ModelIdentity,
+        epsilon: f32,
+        min_points: usize,
+        metric: DistanceMetric,
+    ) -> Self {
+        let now = Utc::now();
+        Self {
+            version: MODEL_FORMAT_VERSION,
+            metadata: ModelMetadata {
+                embedding_model: Some(identity.embedding_model.clone()),
+                embedding_fingerprint: Some(identity.embedding_fingerprint.clone()),
+                patterns_fingerprint: Some(identity.patterns_fingerprint.clone()),
+                algorithm: ClusteringAlgorithm::default(),
+                epsilon: Some(epsilon),
+                min_points: Some(min_points),
+                metric,
+                dimensions,
+                created_at: now,
+                updated_at: now,
+            },
+            centroids: Array2::zeros((0, dimensions)),
+            clusters: Vec::new(),
+            next_cluster_id: 0,
+        }
+    }
+
+    /// Loads a model file, migrating older formats to the current one.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the model file.
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path)?;
+        let value: serde_json::Value = serde_json::from_reader(BufReader::new(file))?;
+        let version = value.get("version").and_then(|v| v.as_u64());
+        match version {
+            Some(v) if (1..=MODEL_FORMAT_VERSION as u64).contains(&v) => {
+                let mut model: Self = serde_json::from_value(value)?;
+                if v < MODEL_FORMAT_VERSION as u64 {
+                    info!("Migrating model file {path} from version {v} to {MODEL_FORMAT_VERSION}.");
+                    model.version = MODEL_FORMAT_VERSION;
+                }
+                model.assign_missing_clusters()?;
+                Ok(model)
+            }
+            Some(v) => Err(E::msg(format!(
+                "Model file {path} has unsupported format version {v} (expected {MODEL_FORMAT_VERSION})"
+            ))),
+            None => {
+                let centroids: Array2<f32> = serde_json::from_value(value)?;
+                warn!(
+                    "Model file {path} uses the legacy centroids-only format; migrating to version {MODEL_FORMAT_VERSION}. \
+                     Its embedding model and patterns are unknown and cannot be verified."
+                );
+                let modified: DateTime<Utc> = std::fs::metadata(path)?.modified()?.into();
+                let mut model = Self::from_legacy(centroids, modified);
+                model.assign_missing_clusters()?;
+                Ok(model)
+            }
+        }
+    }
+
+    /// Wraps a legacy bare centroid matrix in a model with unknown provenance.
+    fn from_legacy(centroids: Array2<f32>, modified: DateTime<Utc>) -> Self {
+        Self {
+            version: MODEL_FORMAT_VERSION,
+            metadata: ModelMetadata {
+                embedding_model: None,
+                embedding_fingerprint: None,
+                patterns_fingerprint: None,
+                algorithm: ClusteringAlgorithm::Dbscan,
+                epsilon: None,
+                min_points: None,
+                metric: DistanceMetric::Euclidean,
+                dimensions: centroids.ncols(),
+                created_at: modified,
+                updated_at: modified,
+            },
+            centroids,
+            clusters: Vec::new(),
+            next_cluster_id: 0,
+        }
+    }
+
+    /// Gives every centroid without cluster information a fresh cluster with a new ID.
+    ///
+    /// Used when migrating from formats that did not store per-cluster information.
+    fn assign_missing_clusters(&mut self) -> Result<()> {
+        if self.clusters.len() > self.centroids.nrows() {
+            return Err(E::msg(format!(
+                "Model has {} clusters but only {} centroids",
+                self.clusters.len(),
+                self.centroids.nrows()
+            )));
+        }
+        let max_id = self.clusters.iter().map(|c| c.id + 1).max().unwrap_or(0);
+        self.next_cluster_id = self.next_cluster_id.max(max_id);
+        while self.clusters.len() < self.centroids.nrows() {
+            let cluster = self.new_cluster();
+            self.clusters.push(cluster);
+        }
+        Ok(())
+    }
+
+    /// Creates an empty cluster with the next unused ID.
+    fn new_cluster(&mut self) -> Cluster {
+        let id = self.next_cluster_id;
+        self.next_cluster_id += 1;
+        Cluster {
+            id,
+            label: None,
+            template: None,
+            hits: 0,
+            radius: None,
+            first_seen: None,
+            last_seen: None,
+            exemplars: Vec::new(),
+            params: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a new cluster with the given centroid and returns it for further updates.
+    ///
+    /// The centroid is normalized if the model uses the cosine metric.
+    ///
+    /// # Arguments
+    ///
+    /// * `centroid` - The centroid of the new cluster.
+    pub fn add_cluster(&mut self, centroid: ArrayView1<f32>) -> Result<&mut Cluster> {
+        self.centroids
+            .push_row(centroid)
+            .map_err(|e| E::msg(e.to_string()))?;
+        let last = self.centroids.nrows() - 1;
+        self.metadata.metric.normalize(self.centroids.row_mut(last));
+        let cluster = self.new_cluster();
+        self.clusters.push(cluster);
+        Ok(self.clusters.last_mut().expect("cluster was just added"))
+    }
+
+    /// Finds the centroids closest to an embedding, using the distance metric of the model.
+    ///
+    /// Returns `None` if the model has no clusters.
+    ///
+    /// # Arguments
+    ///
+    /// * `embedding` - The embedding of a log message.
+    pub fn nearest(&self, embedding: ArrayView1<f32>) -> Result<Option<Nearest>> {
+        let mut nearest: Option<Nearest> = None;
+        for (i, centroid) in self.centroids.axis_iter(Axis(0)).enumerate() {
+            let dist = self.metadata.metric.distance(centroid, embedding)?;
+            match &mut nearest {
+                None => {
+                    nearest = Some(Nearest {
+                        index: i,
+                        distance: dist,
+                        second_distance: None,
+                    })
+                }
+                Some(best) if dist < best.distance => {
+                    best.second_distance = Some(best.distance);
+                    best.index = i;
+                    best.distance = dist;
+                }
+                Some(best) => {
+                    if best.second_distance.is_none_or(|second| dist < second) {
+                        best.second_distance = Some(dist);
+                    }
+                }
+            }
+        }
+        Ok(nearest)
+    }
+
+    /// Finds the cluster with the given ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The stable cluster ID.
+    pub fn cluster_mut(&mut self, id: u64) -> Option<&mut Cluster> {
+        self.clusters.iter_mut().find(|c| c.id == id)
+    }
+
+    /// Saves the model to a file in JSON format.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path to the file where the model will be saved.
+    pub fn save(&self, path: &str) -> Result<()> {
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Records that the model has been modified, adopting the given identity for any
+    /// provenance that was previously unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The embedding model and patterns used for the update.
+    pub fn touch(&mut self, identity: &ModelIdentity) {
+        let metadata = &mut self.metadata;
+        metadata
+            .embedding_model
+            .get_or_insert_with(|| identity.embedding_model.clone());
+        metadata
+            .embedding_fingerprint
+            .get_or_insert_with(|| identity.embedding_fingerprint.clone());
+        metadata
+            .patterns_fingerprint
+            .get_or_insert_with(|| identity.patterns_fingerprint.clone());
+        metadata.updated_at = Utc::now();
+    }
+
+    /// Checks that the model was built with the given embedding model and patterns.
+    ///
+    /// Mismatches are reported as an error, or only logged as warnings when `allow_mismatch` is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The embedding model and patterns currently in use.
+    /// * `allow_mismatch` - Whether to continue with a warning instead of failing.
+    pub fn check_compatible(&self, identity: &ModelIdentity, allow_mismatch: bool) -> Result<()> {
+        let metadata = &self.metadata;
+        let mut problems = Vec::new();
+        if let Some(fingerprint) = &metadata.embedding_fingerprint {
+            if *fingerprint != identity.embedding_fingerprint {
+                problems.push(format!(
+                    "embedding model differs: model was built with {} ({}), current is {} ({})",
+                    metadata.embedding_model.as_deref().unwrap_or("unknown"),
+                    short(fingerprint),
+                    identity.embedding_model,
+                    short(&identity.embedding_fingerprint)
+                ));
+            }
+        }
+        if let Some(fingerprint) = &metadata.patterns_fingerprint {
+            if *fingerprint != identity.patterns_fingerprint {
+                problems.push(format!(
+                    "preprocessing patterns differ: model was built with {}, current is {}",
+                    short(fingerprint),
+                    short(&identity.patterns_fingerprint)
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            info!(
+                "Model built with {} at {}, last updated {}.",
+                metadata
+                    .embedding_model
+                    .as_deref()
+                    .unwrap_or("an unknown embedding model"),
+                metadata.created_at,
+                metadata.updated_at
+            );
+            return Ok(());
+        }
+        if allow_mismatch {
+            for problem in &problems {
+                warn!("MODEL MISMATCH: {problem}. Results may be meaningless.");
+            }
+            Ok(())
+        } else {
+            Err(E::msg(format!(
+                "Model is incompatible with the current setup: {}. Use --allow-mismatch to continue anyway.",
+                problems.join("; ")
+            )))
+        }
+    }
+}
+
+/// Shortens a fingerprint for display.
+fn short(fingerprint: &str) -> &str {
+    &fingerprint[..fingerprint.len().min(12)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use tempfile::NamedTempFile;
+
+    fn identity(patterns: &str) -> ModelIdentity {
+        ModelIdentity {
+            embedding_model: "test-model".to_string(),
+            embedding_fingerprint: fingerprint([b"weights".as_slice()]),
+            patterns_fingerprint: fingerprint([patterns.as_bytes()]),
+        }
+    }
+
+    #[test]
+    fn test_streamed_file_fingerprint_matches_in_memory_fingerprint() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"model weights")?;
+        let mut fingerprinter = Fingerprinter::default();
+        fingerprinter.add(b"config");
+        fingerprinter.add_file(file.path())?;
+        assert_eq!(
+            fingerprinter.finish(),
+            fingerprint([b"config".as_slice(), b"model weights".as_slice()])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_file_is_migrated() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let path = file.path().to_str().unwrap();
+        let centroids = array![[1.0f32, 0.0], [0.0, 1.0]];
+        serde_json::to_writer(File::create(path)?, &centroids)?;
+
+        let mut model = LogModel::load(path)?;
+        assert_eq!(model.centroids, centroids);
+        assert_eq!(model.metadata.dimensions, 2);
+        assert!(model.metadata.embedding_fingerprint.is_none());
+        let ids: Vec<u64> = model.clusters.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [0, 1]);
+        model.check_compatible(&identity("a"), false)?;
+
+        model.touch(&identity("a"));
+        model.save(path)?;
+        let model = LogModel::load(path)?;
+        assert_eq!(model.version, MODEL_FORMAT_VERSION);
+        assert!(model.check_compatible(&identity("b"), false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cluster_ids_are_stable() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let path = file.path().to_str().unwrap();
+        let mut model = LogModel::new(2, &identity("a"), 0.5, 3, DistanceMetric::Euclidean);
+        model.add_cluster(array![1.0f32, 0.0].view())?.label = Some("ssh".to_string());
+        let seen = Utc::now();
+        let cluster = model.add_cluster(array![0.0f32, 1.0].view())?;
+        cluster.record_hit("line", seen);
+        cluster.record_hit("line", seen);
+        model.save(path)?;
+
+        let mut model = LogModel::load(path)?;
+        assert_eq!(model.clusters[0].display_name(), "0 (ssh)");
+        model.clusters[0].template = Some("sshd <*>".to_string());
+        assert_eq!(model.clusters[0].display_name(), "0 (ssh) [sshd <*>]");
+        assert_eq!(model.clusters[1].hits, 2);
+        assert_eq!(model.clusters[1].exemplars, ["line"]);
+        assert_eq!(model.clusters[1].last_seen, Some(seen));
+        let param = |value: &str| ("ip".to_string(), value.to_string());
+        let cluster = &mut model.clusters[1];
+        cluster.record_params(&[param("10.0.0.1")]);
+        cluster.record_params(&[param("10.0.0.2"), param("10.0.0.1")]);
+        for i in 0..MAX_PARAM_VALUES {
+            cluster.record_params(&[param(&format!("192.168.0.{i}"))]);
+        }
+        let ips = &cluster.params["ip"];
+        assert_eq!(ips.top(2), [("10.0.0.1", 2), ("10.0.0.2", 1)]);
+        assert_eq!(ips.counts.len(), MAX_PARAM_VALUES);
+        assert_eq!(ips.other, 2);
+        assert_eq!(model.add_cluster(array![1.0f32, 1.0].view())?.id, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mismatch_can_be_allowed() {
+        let model = LogModel::new(2, &identity("a"), 0.5, 3, DistanceMetric::Euclidean);
+        assert!(model.check_compatible(&identity("a"), false).is_ok());
+        assert!(model.check_compatible(&identity("b"), false).is_err());
+        assert!(model.check_compatible(&identity("b"), true).is_ok());
+    }
+
+    #[test]
+    fn test_cosine_metric_keeps_centroids_normalized() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let path = file.path().to_str().unwrap();
+        let mut model = LogModel::new(2, &identity("a"), 0.1, 3, DistanceMetric::Cosine);
+        model.add_cluster(array![3.0f32, 0.0].view())?;
+        model.add_cluster(array![0.0f32, 2.0].view())?;
+        assert_eq!(model.centroids, array![[1.0f32, 0.0], [0.0, 1.0]]);
+
+        let nearest = model.nearest(array![5.0f32, 5.0].view())?.unwrap();
+        assert!((nearest.distance - (1.0 - 0.5f64.sqrt())).abs() < 1e-6);
+        assert_eq!(nearest.second_distance, Some(nearest.distance));
+
+        model.save(path)?;
+        assert_eq!(
+            LogModel::load(path)?.metadata.metric,
+            DistanceMetric::Cosine
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_threshold_uses_cluster_radius() {
+        // 19 of 20 lines lie within 0.2 of the centroid.
+        let radius = cluster_radius(&[(0.3, 1), (0.1, 10), (0.2, 9)]);
+        assert_eq!(radius, Some(0.2));
+        assert_eq!(cluster_radius(&[(0.0, 7)]), None);
+
+        let mut model = LogModel::new(2, &identity("a"), 0.5, 3, DistanceMetric::Euclidean);
+        let mut tight = model.new_cluster();
+        tight.radius = radius;
+        let unknown = model.new_cluster();
+        let threshold = MatchThreshold::Radius {
+            multiplier: 1.5,
+            fallback: 0.5,
+        };
+        assert!((threshold.for_cluster(&tight) - 0.3).abs() < 1e-9);
+        assert_eq!(threshold.for_cluster(&unknown), 0.5);
+        assert_eq!(MatchThreshold::Global(1.0).for_cluster(&tight), 1.0);
+
+        // Lines at 0.25 grow the radius, lines at 0.1 shrink it.
+        tight.update_radius(0.25, 0.1);
+        assert!(tight.radius.unwrap() > 0.2);
+        for _ in 0..100 {
+            tight.update_radius(0.1, 0.1);
+        }
+        assert!(tight.radius.unwrap() < 0.2);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::model::{cluster_name, LogModel, Nearest};
+use anyhow::{Error as E, Result};
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+
+/// The format of per-line results written by `ingest` and `classify`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Tab-separated verdict, cluster, distance and original line.
+    #[default]
+    Text,
+    /// One JSON object per line (JSON Lines).
+    Jsonl,
+}
+
+impl FromStr for OutputFormat {
+    type Err = E;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "jsonl" | "json" => Ok(Self::Jsonl),
+            _ => Err(E::msg(format!(
+                "Unknown output format '{name}', expected text or jsonl"
+            ))),
+        }
+    }
+}
+
+/// Whether a log line matched a cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    /// The line is within the threshold of its nearest cluster.
+    Matched,
+    /// The line is not close enough to any cluster.
+    Unmatched,
+}
+
+impl Verdict {
+    /// Returns the verdict as written in text output.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Matched => "matched",
+            Self::Unmatched => "unmatched",
+        }
+    }
+}
+
+/// Serializes parameters as an object mapping each name to the list of its values in the line.
+fn serialize_params<S: Serializer>(
+    params: &&[(String, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut grouped: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (name, value) in params.iter() {
+        grouped.entry(name).or_default().push(value);
+    }
+    grouped.serialize(serializer)
+}
+
+/// The result of matching a single log line against the model.
+#[derive(Debug, Serialize)]
+pub struct MatchRecord<'a> {
+    /// The original log line.
+    pub line: &'a str,
+    /// The log line after preprocessing.
+    pub preprocessed: &'a str,
+    /// The timestamp found in the line, if any.
+    pub timestamp: Option<DateTime<Utc>>,
+    /// The stable ID of the nearest cluster.
+    pub cluster_id: Option<u64>,
+    /// The label of the nearest cluster.
+    pub cluster_label: Option<&'a str>,
+    /// The template of the nearest cluster.
+    pub cluster_template: Option<&'a str>,
+    /// The values replaced by named placeholders during preprocessing, grouped by name.
+    #[serde(serialize_with = "serialize_params")]
+    pub params: &'a [(String, String)],
+    /// The distance to the nearest cluster's centroid.
+    pub distance: Option<f64>,
+    /// The distance to the second-nearest cluster's centroid.
+    pub second_distance: Option<f64>,
+    /// Whether the line matched its nearest cluster.
+    pub verdict: Verdict,
+}
+
+impl<'a> MatchRecord<'a> {
+    /// Describes the result of matching a log line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The original log line.
+    /// * `preprocessed` - The log line after preprocessing.
+    /// * `params` - The parameters extracted during preprocessing.
+    /// * `timestamp` - The timestamp found in the line, if any.
+    /// * `nearest` - The nearest clusters, or `None` if the model has no clusters.
+    /// * `log_model` - The model the line was matched against.
+    /// * `verdict` - Whether the line matched its nearest cluster.
+    pub fn new(
+        line: &'a str,
+        preprocessed: &'a str,
+        params: &'a [(String, String)],
+        timestamp: Option<DateTime<Utc>>,
+        nearest: Option<&Nearest>,
+        log_model: &'a LogModel,
+        verdict: Verdict,
+    ) -> Self {
+        let cluster = nearest.map(|nearest| &log_model.clusters[nearest.index]);
+        Self {
+            line,
+            preprocessed,
+            params,
+            timestamp,
+            cluster_id: cluster.map(|c| c.id),
+            cluster_label: cluster.and_then(|c| c.label.as_deref()),
+            cluster_template: cluster.and_then(|c| c.template.as_deref()),
+            distance: nearest.map(|n| n.distance),
+            second_distance: nearest.and_then(|n| n.second_distance),
+            verdict,
+        }
+    }
+
+    /// Writes the record as a single line in the given format.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - The output format.
+    /// * `out` - Where to write the record.
+    pub fn write(&self, format: OutputFormat, out: &mut impl Write) -> Result<()> {
+        match format {
+            OutputFormat::Text => {
+                let cluster = self.cluster_id.map_or_else(
+                    || "-".to_string(),
+                    |id| cluster_name(id, self.cluster_label, self.cluster_template),
+                );
+                let distance = self.distance.unwrap_or(f64::INFINITY);
+                writeln!(
+                    out,
+                    "{}\t{cluster}\t{distance:.4}\t{}",
+                    self.verdict.as_str(),
+                    self.line
+                )?;
+            }
+            OutputFormat::Jsonl => {
+                serde_json::to_writer(&mut *out, self)?;
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{fingerprint, DistanceMetric, ModelIdentity};
+    use ndarray::array;
+
+    #[test]
+    fn test_jsonl_record_reports_nearest_clusters() -> Result<()> {
+        let identity = ModelIdentity {
+            embedding_model: "test-model".to_string(),
+            embedding_fingerprint: fingerprint([b"weights".as_slice()]),
+            patterns_fingerprint: fingerprint([b"patterns".as_slice()]),
+        };
+        let mut model = LogModel::new(2, &identity, 0.5, 2, DistanceMetric::Euclidean);
+        model.add_cluster(array![0.0f32, 0.0].view())?;
+        let far = model.add_cluster(array![3.0f32, 4.0].view())?;
+        far.label = Some("far".to_string());
+        far.template = Some("far <*>".to_string());
+
+        let nearest = model.nearest(array![3.0f32, 4.0].view())?.unwrap();
+        assert_eq!(nearest.index, 1);
+        assert_eq!(nearest.second_distance, Some(5.0));
+
+        let params = [
+            ("user".to_string(), "bob".to_string()),
+            ("ip".to_string(), "10.0.0.1".to_string()),
+            ("ip".to_string(), "10.0.0.2".to_string()),
+        ];
+        let record = MatchRecord::new(
+            "raw",
+            "pre",
+            &params,
+            None,
+            Some(&nearest),
+            &model,
+            Verdict::Matched,
+        );
+        let mut out = Vec::new();
+        record.write(OutputFormat::Jsonl, &mut out)?;
+        let json: serde_json::Value = serde_json::from_slice(&out)?;
+        assert_eq!(json["cluster_id"], 1);
+        assert_eq!(json["cluster_label"], "far");
+        assert_eq!(json["cluster_template"], "far <*>");
+        assert_eq!(json["distance"], 0.0);
+        assert_eq!(json["verdict"], "matched");
+        assert_eq!(
+            json["params"],
+            serde_json::json!({"ip": ["10.0.0.1", "10.0.0.2"], "user": ["bob"]})
+        );
+        assert!(json["timestamp"].is_null());
+
+        let mut out = Vec::new();
+        record.write(OutputFormat::Text, &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "matched\t1 (far) [far <*>]\t0.0000\traw\n"
+        );
+        Ok(())
+    }
+}
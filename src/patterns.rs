@@ -0,0 +1,343 @@
+use anyhow::{Error as E, Result};
+
+/// The version of the built-in pattern library, increased whenever a pattern changes.
+///
+/// Changed patterns also change the patterns fingerprint recorded in models, so a model is
+/// never silently matched with different built-in patterns than it was trained with.
+pub const BUILTIN_PATTERNS_VERSION: u32 = 1;
+
+/// The selector enabling every built-in pattern.
+pub const ALL_BUILTIN: &str = "all";
+
+/// A named, built-in normalization pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuiltinPattern {
+    /// The name used to enable the pattern.
+    pub name: &'static str,
+    /// The group the pattern belongs to, which enables all of its patterns at once.
+    pub group: &'static str,
+    /// What the pattern replaces.
+    pub description: &'static str,
+    /// The regex matching the values to replace.
+    pub regex: &'static str,
+    /// The replacement, in the same syntax as in a patterns file.
+    pub replacement: &'static str,
+}
+
+/// The built-in patterns, in the order they are applied.
+///
+/// Specific patterns come before the more general ones they overlap with, e.g. timestamps and
+/// addresses before plain numbers.
+pub const BUILTIN_PATTERNS: &[BuiltinPattern] = &[
+    BuiltinPattern {
+        name: "syslog_header",
+        group: "time",
+        description: "The timestamp and host of a traditional syslog line",
+        regex: r"^[A-Z][a-z]{2} +\d{1,2} \d{2}:\d{2}:\d{2} (?P<host>\S+) ",
+        replacement: "<DATE> <HOST:host> ",
+    },
+    BuiltinPattern {
+        name: "iso_date",
+        group: "time",
+        description: "ISO 8601 dates and timestamps",
+        regex: r"\b\d{4}-\d{2}-\d{2}(?:[T ]\d{2}:\d{2}(?::\d{2}(?:[.,]\d+)?)?(?:Z|[+-]\d{2}:?\d{2})?)?\b",
+        replacement: "<DATE>",
+    },
+    BuiltinPattern {
+        name: "uuid",
+        group: "ids",
+        description: "UUIDs",
+        regex: r"(?i)\b[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}\b",
+        replacement: "<UUID>",
+    },
+    BuiltinPattern {
+        name: "mac",
+        group: "network",
+        description: "MAC addresses",
+        regex: r"(?i)\b[0-9a-f]{2}(?:[:-][0-9a-f]{2}){5}\b",
+        replacement: "<MAC>",
+    },
+    BuiltinPattern {
+        name: "ipv6",
+        group: "network",
+        description: "IPv6 addresses, including compressed forms",
+        regex: r"(?i)\b(?:[0-9a-f]{1,4}:){7}[0-9a-f]{1,4}\b|\b(?:[0-9a-f]{1,4}:){1,6}(?::[0-9a-f]{1,4}){1,6}\b|\b(?:[0-9a-f]{1,4}:){1,7}:|\B::(?:[0-9a-f]{1,4}:){0,5}[0-9a-f]{1,4}\b",
+        replacement: "<IP:ip>",
+    },
+    BuiltinPattern {
+        name: "ipv4",
+        group: "network",
+        description: "IPv4 addresses",
+        regex: r"\b(?:\d{1,3}\.){3}\d{1,3}\b",
+        replacement: "<IP:ip>",
+    },
+    BuiltinPattern {
+        name: "port",
+        group: "network",
+        description: "Port numbers after an IP address or the word port",
+        regex: r"(?P<prefix>(?i:\bport)[ =:]?|<IP>\]?:)(?P<port>\d{1,5})\b",
+        replacement: "${prefix}<PORT:port>",
+    },
+    BuiltinPattern {
+        name: "email",
+        group: "network",
+        description: "Email addresses",
+        regex: r"\b[\w.+-]+@[\w-]+(?:\.[\w-]+)+\b",
+        replacement: "<EMAIL>",
+    },
+    BuiltinPattern {
+        name: "path",
+        group: "files",
+        description: "Absolute Unix file paths",
+        regex: r#"(?P<lead>^|[\s=("'])(?:/[\w.@+-]+)+/?"#,
+        replacement: "${lead}<PATH>",
+    },
+    BuiltinPattern {
+        name: "hex",
+        group: "ids",
+        description: "Hexadecimal numbers and memory addresses such as 0x7ffd1234",
+        regex: r"(?i)\b0x[0-9a-f]+\b",
+        replacement: "<HEX>",
+    },
+    BuiltinPattern {
+        name: "pid",
+        group: "ids",
+        description: "Process IDs, as in sshd[42] or pid=42",
+        regex: r"(?P<prefix>\w\[|(?i:\bpid)[=: ])(?P<pid>\d+)\b",
+        replacement: "${prefix}<PID:pid>",
+    },
+    BuiltinPattern {
+        name: "duration",
+        group: "quantities",
+        description: "Durations with a unit, such as 250ms or 3.5 seconds",
+        regex: r"(?i)\b\d+(?:\.\d+)?\s?(?:ns|us|µs|ms|s|secs?|seconds?|m|mins?|minutes?|h|hrs?|hours?)\b",
+        replacement: "<DURATION>",
+    },
+    BuiltinPattern {
+        name: "size",
+        group: "quantities",
+        description: "Byte sizes, such as 512 KB or 1.5GiB",
+        regex: r"(?i)\b\d+(?:\.\d+)?\s?(?:[kmgtp]i?b|b|bytes?)\b",
+        replacement: "<SIZE>",
+    },
+    BuiltinPattern {
+        name: "number",
+        group: "quantities",
+        description: "Integers and decimal numbers",
+        regex: r"\b\d+(?:\.\d+)?\b",
+        replacement: "<NUM>",
+    },
+];
+
+/// Selects built-in patterns by name or group, or all of them with `all`.
+///
+/// The patterns are returned in library order, whatever the order of the selectors, since later
+/// patterns rely on earlier ones having replaced more specific values.
+///
+/// # Arguments
+///
+/// * `selectors` - The names of patterns or groups.
+pub fn select_builtin<S: AsRef<str>>(selectors: &[S]) -> Result<Vec<&'static BuiltinPattern>> {
+    for selector in selectors {
+        let selector = selector.as_ref().trim();
+        if selector != ALL_BUILTIN
+            && !BUILTIN_PATTERNS
+                .iter()
+                .any(|p| p.name == selector || p.group == selector)
+        {
+            return Err(E::msg(format!(
+                "Unknown built-in pattern or group '{selector}'"
+            )));
+        }
+    }
+    Ok(BUILTIN_PATTERNS
+        .iter()
+        .filter(|pattern| {
+            selectors.iter().any(|selector| {
+                let selector = selector.as_ref().trim();
+                selector == ALL_BUILTIN || selector == pattern.name || selector == pattern.group
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessing::LogPreprocessor;
+
+    /// Applies the selected built-in patterns to a message.
+    fn apply(selectors: &[&str], message: &str) -> String {
+        LogPreprocessor::builtin(selectors)
+            .unwrap()
+            .preprocess(message)
+    }
+
+    #[test]
+    fn test_selectors() -> Result<()> {
+        let names = |selectors: &[&str]| -> Result<Vec<&str>> {
+            Ok(select_builtin(selectors)?.iter().map(|p| p.name).collect())
+        };
+        assert_eq!(names(&["number", "ipv4"])?, ["ipv4", "number"]);
+        assert_eq!(names(&["time", "iso_date"])?, ["syslog_header", "iso_date"]);
+        assert_eq!(names(&["all"])?.len(), BUILTIN_PATTERNS.len());
+        assert!(select_builtin(&["ipv5"]).is_err());
+        for pattern in BUILTIN_PATTERNS {
+            regex::Regex::new(pattern.regex)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_syslog_header() {
+        assert_eq!(
+            apply(&["syslog_header"], "Jan  5 10:00:00 web-1 sshd[42]: ok"),
+            "<DATE> <HOST> sshd[42]: ok"
+        );
+        assert_eq!(
+            apply(&["syslog_header"], "see Jan 5 10:00:00 web-1"),
+            "see Jan 5 10:00:00 web-1"
+        );
+    }
+
+    #[test]
+    fn test_iso_date() {
+        assert_eq!(
+            apply(&["iso_date"], "at 2025-01-05T09:00:00.123+01:00 done"),
+            "at <DATE> done"
+        );
+        assert_eq!(apply(&["iso_date"], "2025-01-05 09:00:00Z"), "<DATE>");
+        assert_eq!(apply(&["iso_date"], "day 2025-01-05"), "day <DATE>");
+    }
+
+    #[test]
+    fn test_uuid() {
+        assert_eq!(
+            apply(
+                &["uuid"],
+                "request 550E8400-e29b-41d4-a716-446655440000 failed"
+            ),
+            "request <UUID> failed"
+        );
+        assert_eq!(apply(&["uuid"], "id 550e8400-e29b"), "id 550e8400-e29b");
+    }
+
+    #[test]
+    fn test_mac() {
+        assert_eq!(
+            apply(&["mac"], "link 00:1A:2b:3c:4d:5e up"),
+            "link <MAC> up"
+        );
+        assert_eq!(
+            apply(&["mac"], "link 00-1a-2b-3c-4d-5e up"),
+            "link <MAC> up"
+        );
+        assert_eq!(apply(&["mac"], "at 10:00:00"), "at 10:00:00");
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let apply = |message| apply(&["ipv6"], message);
+        assert_eq!(apply("from 2001:db8:0:0:0:0:2:1 ok"), "from <IP> ok");
+        assert_eq!(apply("from fe80::1ff:fe23:4567:890a ok"), "from <IP> ok");
+        assert_eq!(apply("listen [::1]:8080"), "listen [<IP>]:8080");
+        assert_eq!(apply("prefix 2001:db8:: ok"), "prefix <IP> ok");
+        assert_eq!(apply("at 10:00:00 in Foo::add"), "at 10:00:00 in Foo::add");
+    }
+
+    #[test]
+    fn test_ipv4() {
+        assert_eq!(
+            apply(&["ipv4"], "from 192.168.1.1 to 10.0.0.255"),
+            "from <IP> to <IP>"
+        );
+        assert_eq!(apply(&["ipv4"], "version 1.2.3"), "version 1.2.3");
+    }
+
+    #[test]
+    fn test_port() {
+        assert_eq!(
+            apply(&["network"], "from 10.0.0.1:5432 port 22"),
+            "from <IP>:<PORT> port <PORT>"
+        );
+        assert_eq!(apply(&["network"], "[::1]:8080"), "[<IP>]:<PORT>");
+        assert_eq!(apply(&["port"], "Port=443"), "Port=<PORT>");
+        assert_eq!(apply(&["port"], "support 22"), "support 22");
+    }
+
+    #[test]
+    fn test_email() {
+        assert_eq!(
+            apply(&["email"], "mail to first.last+tag@mail.example.com sent"),
+            "mail to <EMAIL> sent"
+        );
+        assert_eq!(apply(&["email"], "user@localhost"), "user@localhost");
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            apply(&["path"], "open /var/log/app-1.log failed, file=/tmp/x/"),
+            "open <PATH> failed, file=<PATH>"
+        );
+        assert_eq!(
+            apply(&["path"], "GET http://example.com/a/b"),
+            "GET http://example.com/a/b"
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(
+            apply(&["hex"], "segfault at 0x7FFD1234 ip 0xdeadbeef"),
+            "segfault at <HEX> ip <HEX>"
+        );
+        assert_eq!(apply(&["hex"], "value 0xyz"), "value 0xyz");
+    }
+
+    #[test]
+    fn test_pid() {
+        assert_eq!(apply(&["pid"], "sshd[12345]: ok"), "sshd[<PID>]: ok");
+        assert_eq!(apply(&["pid"], "killed PID 42"), "killed PID <PID>");
+        assert_eq!(apply(&["pid"], "pid=42 exited"), "pid=<PID> exited");
+        assert_eq!(apply(&["pid"], "rapid 42"), "rapid 42");
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(
+            apply(&["duration"], "took 250ms, then 3.5 seconds and 2h"),
+            "took <DURATION>, then <DURATION> and <DURATION>"
+        );
+        assert_eq!(apply(&["duration"], "5 sectors"), "5 sectors");
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(
+            apply(&["size"], "wrote 512 KB of 1.5GiB (42 bytes)"),
+            "wrote <SIZE> of <SIZE> (<SIZE>)"
+        );
+        assert_eq!(apply(&["size"], "42 bits"), "42 bits");
+    }
+
+    #[test]
+    fn test_number() {
+        assert_eq!(
+            apply(&["number"], "retry 3 of 10 after 0.5"),
+            "retry <NUM> of <NUM> after <NUM>"
+        );
+        assert_eq!(apply(&["number"], "disk sda1"), "disk sda1");
+    }
+
+    #[test]
+    fn test_all_patterns_together() {
+        let message = "Jan  5 10:00:00 web-1 sshd[42]: Accepted publickey for bob from \
+                       10.0.0.1 port 22 after 15ms at 2025-01-05T09:00:00Z, retry 3";
+        assert_eq!(
+            apply(&["all"], message),
+            "<DATE> <HOST> sshd[<PID>]: Accepted publickey for bob from <IP> port <PORT> \
+             after <DURATION> at <DATE>, retry <NUM>"
+        );
+    }
+}
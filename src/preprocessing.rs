@@ -1,38 +1,548 @@
-use anyhow::Result;
-use regex::Regex;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use crate::model::fingerprint;
+use crate::patterns::select_builtin;
+use anyhow::{Error as E, Result};
+use log::warn;
+use regex::{Captures, Regex, RegexSet};
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::LazyLock;
+use toml_edit::{Document, Item, TableLike};
+
+/// Matches a named placeholder in a replacement, e.g. `<IP:src>`.
+static NAMED_PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"<([^<>:\s]+):([A-Za-z_][A-Za-z0-9_]*)>").expect("valid placeholder regex")
+});
+
+/// Matches the program name (tag) of a syslog line, with or without the priority, timestamp
+/// and host in front of it, e.g. `sshd` in `Jan  5 10:00:00 web-1 sshd[42]: ...`.
+static SYSLOG_PROGRAM: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"^(?:<\d+>\d?\s*)?",
+        r"(?:(?:[A-Z][a-z]{2} +\d{1,2} \d{2}:\d{2}:\d{2}|\d{4}-\d{2}-\d{2}T\S+) \S+ )?",
+        r"(?P<program>[^\s\[\]:]+)(?:\[\d+\])?: "
+    ))
+    .expect("valid syslog program regex")
+});
+
+/// The patterns file used when none is given.
+pub const DEFAULT_PATTERNS_FILE: &str = "patterns.txt";
+
+/// The directive enabling built-in patterns in a patterns file, e.g. `@builtin network, uuid`.
+pub const BUILTIN_DIRECTIVE: &str = "@builtin";
+
+/// The keys allowed in a `[[pattern]]` entry of a structured patterns file.
+const ENTRY_KEYS: [&str; 9] = [
+    "name",
+    "description",
+    "regex",
+    "replacement",
+    "flags",
+    "enabled",
+    "applies_to",
+    "builtin",
+    "tests",
+];
+
+/// The regex flags allowed in the `flags` of a pattern.
+const REGEX_FLAGS: &str = "imsUuxR";
+
+/// Returns the program name (tag) of a syslog line, if it has one.
+///
+/// # Arguments
+///
+/// * `line` - The raw log line.
+pub fn syslog_program(line: &str) -> Option<&str> {
+    SYSLOG_PROGRAM
+        .captures(line)
+        .and_then(|caps| caps.name("program"))
+        .map(|program| program.as_str())
+}
+
+/// A regex pattern and the text replacing its matches.
+struct Pattern {
+    /// The name of the pattern, or where it was defined.
+    name: String,
+    regex: Regex,
+    /// The replacement with the parameter names removed, e.g. `<IP>` for `<IP:src>`.
+    replacement: String,
+    /// The names of the parameters captured by the pattern.
+    params: Vec<String>,
+    /// The syslog programs whose lines the pattern applies to, or empty for all lines.
+    applies_to: Vec<String>,
+}
+
+impl Pattern {
+    /// Parses the replacement of a pattern, extracting the names of its placeholders.
+    fn new(name: String, regex: Regex, replacement: &str) -> Self {
+        let params = NAMED_PLACEHOLDER
+            .captures_iter(replacement)
+            .map(|caps| caps[2].to_string())
+            .collect();
+        let replacement = NAMED_PLACEHOLDER
+            .replace_all(replacement, "<$1>")
+            .into_owned();
+        Self {
+            name,
+            regex,
+            replacement,
+            params,
+            applies_to: Vec::new(),
+        }
+    }
+
+    /// Returns whether the pattern applies to lines of the given syslog program.
+    fn applies(&self, program: Option<&str>) -> bool {
+        self.applies_to.is_empty()
+            || program.is_some_and(|program| self.applies_to.iter().any(|p| p == program))
+    }
+
+    /// Replaces all matches in a message, recording the values of named placeholders.
+    ///
+    /// The message is borrowed back unchanged if the pattern does not match.
+    fn apply<'t>(
+        &self,
+        message: &'t str,
+        params: Option<&mut Vec<(String, String)>>,
+    ) -> Cow<'t, str> {
+        match params {
+            Some(params) if !self.params.is_empty() => {
+                self.regex.replace_all(message, |caps: &Captures| {
+                    for name in &self.params {
+                        let value = caps
+                            .name(name)
+                            .unwrap_or_else(|| caps.get(0).expect("group 0 is the whole match"));
+                        params.push((name.clone(), value.as_str().to_string()));
+                    }
+                    let mut replaced = String::new();
+                    caps.expand(&self.replacement, &mut replaced);
+                    replaced
+                })
+            }
+            _ => self.regex.replace_all(message, self.replacement.as_str()),
+        }
+    }
+}
+
+/// Returns the syslog program of a message if any of the patterns are scoped to programs.
+fn scoped_program<'t>(patterns: &[Pattern], message: &'t str) -> Option<&'t str> {
+    if patterns.iter().any(|p| !p.applies_to.is_empty()) {
+        syslog_program(message)
+    } else {
+        None
+    }
+}
+
+/// Applies patterns to a message in order, skipping those scoped to other syslog programs.
+///
+/// Every pattern is run over the whole message; this is the reference for `apply_prefiltered`.
+fn apply_patterns(
+    patterns: &[Pattern],
+    message: &str,
+    mut params: Option<&mut Vec<(String, String)>>,
+) -> String {
+    let program = scoped_program(patterns, message);
+    let mut processed_message = message.to_string();
+    for pattern in patterns.iter().filter(|p| p.applies(program)) {
+        processed_message = pattern
+            .apply(&processed_message, params.as_deref_mut())
+            .into_owned();
+    }
+    processed_message
+}
+
+/// Applies patterns to a message in order like `apply_patterns`, but only runs the patterns that
+/// the prefilter reports as matching and only copies the message when a pattern replaces text.
+///
+/// The prefilter matches all patterns in a single pass. Since a replacement can create or remove
+/// matches of later patterns, the prefilter is run again on the message after each replacement.
+fn apply_prefiltered(
+    patterns: &[Pattern],
+    prefilter: &RegexSet,
+    message: &str,
+    mut params: Option<&mut Vec<(String, String)>>,
+) -> String {
+    let program = scoped_program(patterns, message);
+    let mut candidates = prefilter.matches(message);
+    let mut processed_message = Cow::Borrowed(message);
+    for (index, pattern) in patterns.iter().enumerate() {
+        if !candidates.matched(index) || !pattern.applies(program) {
+            continue;
+        }
+        if let Cow::Owned(replaced) = pattern.apply(&processed_message, params.as_deref_mut()) {
+            candidates = prefilter.matches(&replaced);
+            processed_message = Cow::Owned(replaced);
+        }
+    }
+    processed_message.into_owned()
+}
+
+/// Builds the prefilter matching all patterns in a single pass, or `None` if there are no
+/// patterns or the combined regex exceeds the size limit of the regex engine.
+fn build_prefilter(patterns: &[Pattern]) -> Option<RegexSet> {
+    if patterns.is_empty() {
+        return None;
+    }
+    RegexSet::new(patterns.iter().map(|pattern| pattern.regex.as_str()))
+        .map_err(|e| warn!("Applying patterns one by one, cannot combine them: {e}"))
+        .ok()
+}
+
+/// Compiles the selected built-in patterns.
+fn builtin_patterns<S: AsRef<str>>(selectors: &[S]) -> Result<Vec<Pattern>> {
+    select_builtin(selectors)?
+        .into_iter()
+        .map(|builtin| {
+            Ok(Pattern::new(
+                builtin.name.to_string(),
+                Regex::new(builtin.regex)?,
+                builtin.replacement,
+            ))
+        })
+        .collect()
+}
+
+/// Creates an error located at a line of a patterns file.
+fn error_at(line: usize, message: impl Display) -> E {
+    E::msg(format!("line {line}: {message}"))
+}
+
+/// Parses a patterns file in the line format, `regex :: replacement` per line.
+fn parse_line_patterns(text: &str) -> Result<Vec<Pattern>> {
+    let mut patterns = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        if let Some(selectors) = line.trim().strip_prefix(BUILTIN_DIRECTIVE) {
+            let selectors: Vec<&str> = selectors.split(',').collect();
+            patterns.extend(builtin_patterns(&selectors).map_err(|e| error_at(line_number, e))?);
+            continue;
+        }
+        let Some((regex, replacement)) = line.split_once(" :: ") else {
+            return Err(error_at(
+                line_number,
+                "expected `regex :: replacement` or a `@builtin` directive",
+            ));
+        };
+        let regex = Regex::new(regex).map_err(|e| error_at(line_number, e))?;
+        patterns.push(Pattern::new(
+            format!("line {line_number}"),
+            regex,
+            replacement,
+        ));
+    }
+    Ok(patterns)
+}
+
+/// Parses a structured patterns file in TOML format, with one `[[pattern]]` table per entry.
+fn parse_toml_patterns(text: &str) -> Result<Vec<Pattern>> {
+    let document = Document::parse(text).map_err(|e| E::msg(e.to_string()))?;
+    let line_of = |span: Option<Range<usize>>| {
+        span.map_or(0, |span| text[..span.start].matches('\n').count() + 1)
+    };
+    let root = document.as_table();
+    if let Some((key, item)) = root.iter().find(|(key, _)| *key != "pattern") {
+        return Err(error_at(
+            line_of(item.span()),
+            format!("unknown key `{key}`, expected only [[pattern]] tables"),
+        ));
+    }
+    let Some(entries) = root.get("pattern") else {
+        return Ok(Vec::new());
+    };
+    let entries = entries.as_array_of_tables().ok_or_else(|| {
+        error_at(
+            line_of(entries.span()),
+            "`pattern` must be written as [[pattern]] tables",
+        )
+    })?;
+
+    let mut patterns = Vec::new();
+    for entry in entries.iter() {
+        patterns.extend(parse_toml_entry(entry, line_of(entry.span()), &line_of)?);
+    }
+    Ok(patterns)
+}
+
+/// Parses one `[[pattern]]` entry, checks its test cases and returns its patterns, or none if it
+/// is disabled.
+fn parse_toml_entry(
+    entry: &dyn TableLike,
+    line: usize,
+    line_of: &dyn Fn(Option<Range<usize>>) -> usize,
+) -> Result<Vec<Pattern>> {
+    let line_of_key = |key: &str| entry.get(key).map_or(line, |item| line_of(item.span()));
+    if let Some((key, _)) = entry.iter().find(|(key, _)| !ENTRY_KEYS.contains(key)) {
+        return Err(error_at(line_of_key(key), format!("unknown key `{key}`")));
+    }
+    let string = |key: &str| -> Result<Option<&str>> {
+        match entry.get(key) {
+            None => Ok(None),
+            Some(item) => item
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| error_at(line_of_key(key), format!("`{key}` must be a string"))),
+        }
+    };
+    let strings = |key: &str| -> Result<Vec<String>> {
+        let Some(item) = entry.get(key) else {
+            return Ok(Vec::new());
+        };
+        if let Some(value) = item.as_str() {
+            return Ok(vec![value.to_string()]);
+        }
+        item.as_array()
+            .and_then(|array| {
+                array
+                    .iter()
+                    .map(|value| value.as_str().map(str::to_string))
+                    .collect()
+            })
+            .ok_or_else(|| {
+                error_at(
+                    line_of_key(key),
+                    format!("`{key}` must be a string or an array of strings"),
+                )
+            })
+    };
+
+    let name = string("name")?.map_or_else(|| format!("pattern at line {line}"), str::to_string);
+    string("description")?;
+    let enabled = match entry.get("enabled") {
+        None => true,
+        Some(item) => item
+            .as_bool()
+            .ok_or_else(|| error_at(line_of_key("enabled"), "`enabled` must be true or false"))?,
+    };
+
+    let mut patterns = match (string("regex")?, entry.contains_key("builtin")) {
+        (Some(regex), false) => {
+            let replacement = string("replacement")?
+                .ok_or_else(|| error_at(line, format!("pattern `{name}` has no `replacement`")))?;
+            let flags = string("flags")?.unwrap_or("");
+            if let Some(flag) = flags.chars().find(|flag| !REGEX_FLAGS.contains(*flag)) {
+                return Err(error_at(
+                    line_of_key("flags"),
+                    format!("unknown regex flag `{flag}`, expected some of {REGEX_FLAGS}"),
+                ));
+            }
+            let source = if flags.is_empty() {
+                regex.to_string()
+            } else {
+                format!("(?{flags}){regex}")
+            };
+            let regex = Regex::new(&source).map_err(|e| error_at(line_of_key("regex"), e))?;
+            vec![Pattern::new(name.clone(), regex, replacement)]
+        }
+        (None, true) => {
+            if let Some(key) = ["replacement", "flags"]
+                .into_iter()
+                .find(|k| entry.contains_key(k))
+            {
+                return Err(error_at(
+                    line_of_key(key),
+                    format!("`{key}` cannot be combined with `builtin`"),
+                ));
+            }
+            builtin_patterns(&strings("builtin")?)
+                .map_err(|e| error_at(line_of_key("builtin"), e))?
+        }
+        _ => {
+            return Err(error_at(
+                line,
+                format!("pattern `{name}` needs either a `regex` or a `builtin` selection"),
+            ))
+        }
+    };
+    let applies_to = strings("applies_to")?;
+    for pattern in &mut patterns {
+        pattern.applies_to = applies_to.clone();
+    }
+
+    for (test_line, input, expected) in test_cases(entry, line, line_of)? {
+        let actual = apply_patterns(&patterns, &input, None);
+        if actual != expected {
+            return Err(error_at(
+                test_line,
+                format!(
+                    "test of pattern `{name}` failed: `{input}` became `{actual}`, expected `{expected}`"
+                ),
+            ));
+        }
+    }
+    Ok(if enabled { patterns } else { Vec::new() })
+}
+
+/// Reads the test cases of a `[[pattern]]` entry, given as an array of inline tables or as
+/// `[[pattern.tests]]` tables, each with an `input` and the `expected` output.
+fn test_cases(
+    entry: &dyn TableLike,
+    line: usize,
+    line_of: &dyn Fn(Option<Range<usize>>) -> usize,
+) -> Result<Vec<(usize, String, String)>> {
+    let tests: Vec<(&dyn TableLike, usize)> = match entry.get("tests") {
+        None => return Ok(Vec::new()),
+        Some(Item::ArrayOfTables(tables)) => tables
+            .iter()
+            .map(|table| (table as &dyn TableLike, line_of(table.span())))
+            .collect(),
+        Some(item) => item
+            .as_array()
+            .and_then(|array| {
+                array
+                    .iter()
+                    .map(|value| {
+                        let table = value.as_inline_table()?;
+                        Some((table as &dyn TableLike, line_of(value.span())))
+                    })
+                    .collect()
+            })
+            .ok_or_else(|| {
+                error_at(
+                    line_of(item.span()).max(line),
+                    "`tests` must be an array of tables with `input` and `expected`",
+                )
+            })?,
+    };
+    tests
+        .into_iter()
+        .map(|(test, test_line)| {
+            if let Some((key, _)) = test
+                .iter()
+                .find(|(key, _)| *key != "input" && *key != "expected")
+            {
+                return Err(error_at(test_line, format!("unknown key `{key}` in test")));
+            }
+            let field = |key: &str| {
+                test.get(key)
+                    .and_then(Item::as_str)
+                    .map(str::to_string)
+                    .ok_or_else(|| error_at(test_line, format!("test needs a string `{key}`")))
+            };
+            Ok((test_line, field("input")?, field("expected")?))
+        })
+        .collect()
+}
+
+/// The replacements made while preprocessing a message, see `LogPreprocessor::preprocess_traced`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    /// The preprocessed message.
+    pub text: String,
+    /// The byte ranges of the preprocessed message inserted by replacements.
+    pub inserted: Vec<Range<usize>>,
+    /// The byte ranges of the original message that were replaced.
+    pub replaced: Vec<Range<usize>>,
+    /// The patterns that matched, in the order they were applied.
+    pub steps: Vec<TraceStep>,
+}
+
+/// A pattern that matched while preprocessing a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    /// The index of the pattern, see `LogPreprocessor::pattern_names`.
+    pub pattern: usize,
+    /// The number of matches that were replaced.
+    pub matches: usize,
+    /// The message after applying the pattern.
+    pub text: String,
+}
+
+/// Returns the ranges of consecutive set flags.
+fn flagged_ranges(flags: impl IntoIterator<Item = bool>) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (index, flag) in flags.into_iter().enumerate() {
+        match ranges.last_mut() {
+            Some(range) if flag && range.end == index => range.end += 1,
+            _ if flag => ranges.push(index..index + 1),
+            _ => {}
+        }
+    }
+    ranges
+}
 
 /// A preprocessor for log messages that applies a series of regex patterns to normalize the text.
+///
+/// The patterns are applied one after another, each to the output of the previous one. A
+/// `RegexSet` of all patterns is used as a prefilter, so that patterns which cannot match a
+/// message are skipped without running them.
+#[derive(Default)]
 pub struct LogPreprocessor {
-    patterns: Vec<(Regex, String)>,
+    patterns: Vec<Pattern>,
+    /// Matches all patterns at once, if they could be combined.
+    prefilter: Option<RegexSet>,
 }
 
 impl LogPreprocessor {
     /// Creates a new `LogPreprocessor` from a file of regex patterns.
     ///
-    /// Each line in the patterns file should be in the format: `regex :: replacement`.
-    /// Lines starting with `#` or empty lines are ignored.
+    /// Files with the extension `.toml` use the structured format, with one `[[pattern]]` table
+    /// per pattern supporting names, descriptions, regex flags, `enabled`, an `applies_to`
+    /// filter on the syslog program and embedded test cases, which are checked on loading.
+    ///
+    /// Other files use the line format, where each line should be `regex :: replacement`.
+    /// Lines starting with `#` or empty lines are ignored. A line `@builtin <names>` inserts the
+    /// built-in patterns with the given comma-separated names or groups at that position.
+    ///
+    /// In both formats, a placeholder in the replacement can name the value it replaces, e.g.
+    /// `<IP:src>`, so that `preprocess_with_params` reports it. Malformed entries are reported
+    /// with their line number.
     ///
     /// # Arguments
     ///
     /// * `patterns_file` - The path to the file containing the regex patterns.
     pub fn new(patterns_file: &str) -> Result<Self> {
-        let file = File::open(patterns_file)?;
-        let reader = BufReader::new(file);
-        let mut patterns = Vec::new();
-        for line in reader.lines() {
-            let line = line?;
-            if line.starts_with('#') || line.trim().is_empty() {
-                continue;
-            }
-            let parts: Vec<&str> = line.splitn(2, " :: ").collect();
-            if parts.len() == 2 {
-                let re = Regex::new(parts[0])?;
-                patterns.push((re, parts[1].to_string()));
-            }
+        let text = std::fs::read_to_string(patterns_file)?;
+        let is_toml = Path::new(patterns_file)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("toml"));
+        let patterns = if is_toml {
+            parse_toml_patterns(&text)
+        } else {
+            parse_line_patterns(&text)
+        }
+        .map_err(|e| E::msg(format!("Invalid patterns file {patterns_file}: {e}")))?;
+        Ok(Self::from_patterns(patterns))
+    }
+
+    /// Creates a new `LogPreprocessor` from built-in patterns only.
+    ///
+    /// # Arguments
+    ///
+    /// * `selectors` - The names or groups of the built-in patterns, or `all`.
+    pub fn builtin<S: AsRef<str>>(selectors: &[S]) -> Result<Self> {
+        Ok(Self::from_patterns(builtin_patterns(selectors)?))
+    }
+
+    /// Appends built-in patterns, applied after the patterns loaded so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `selectors` - The names or groups of the built-in patterns, or `all`.
+    pub fn add_builtin<S: AsRef<str>>(&mut self, selectors: &[S]) -> Result<()> {
+        self.patterns.extend(builtin_patterns(selectors)?);
+        self.prefilter = build_prefilter(&self.patterns);
+        Ok(())
+    }
+
+    /// Creates a preprocessor applying the given patterns.
+    fn from_patterns(patterns: Vec<Pattern>) -> Self {
+        let prefilter = build_prefilter(&patterns);
+        Self {
+            patterns,
+            prefilter,
+        }
+    }
+
+    /// Applies the patterns, using the prefilter if there is one.
+    fn apply(&self, message: &str, params: Option<&mut Vec<(String, String)>>) -> String {
+        match &self.prefilter {
+            Some(prefilter) => apply_prefiltered(&self.patterns, prefilter, message, params),
+            None => apply_patterns(&self.patterns, message, params),
         }
-        Ok(Self { patterns })
     }
 
     /// Applies the loaded regex patterns to a single log message.
@@ -41,11 +551,134 @@ impl LogPreprocessor {
     ///
     /// * `message` - The log message to preprocess.
     pub fn preprocess(&self, message: &str) -> String {
-        let mut processed_message = message.to_string();
-        for (re, replacement) in &self.patterns {
-            processed_message = re.replace_all(&processed_message, replacement).to_string();
+        self.apply(message, None)
+    }
+
+    /// Applies the loaded regex patterns to a single log message without the prefilter, running
+    /// every pattern over the whole message.
+    ///
+    /// This gives the same result as `preprocess` and is kept as a reference to check and
+    /// benchmark it against.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The log message to preprocess.
+    pub fn preprocess_sequential(&self, message: &str) -> String {
+        apply_patterns(&self.patterns, message, None)
+    }
+
+    /// Applies the loaded regex patterns to a single log message, also returning the values
+    /// replaced by named placeholders.
+    ///
+    /// The parameters are returned as name/value pairs in the order they were replaced; a name
+    /// appears once per replaced value. The value is the capture group of the same name if the
+    /// regex has one, and the whole match otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The log message to preprocess.
+    pub fn preprocess_with_params(&self, message: &str) -> (String, Vec<(String, String)>) {
+        let mut params = Vec::new();
+        let processed_message = self.apply(message, Some(&mut params));
+        (processed_message, params)
+    }
+
+    /// Applies the loaded regex patterns to a single log message, recording which patterns
+    /// matched and which parts of the message they replaced.
+    ///
+    /// The text is the same as returned by `preprocess`. This runs every pattern without the
+    /// prefilter and is meant for inspecting patterns rather than for preprocessing many lines.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The log message to preprocess.
+    pub fn preprocess_traced(&self, message: &str) -> Trace {
+        let program = scoped_program(&self.patterns, message);
+        let mut text = message.to_string();
+        // The byte of the message each byte of the text comes from, or `None` if inserted.
+        let mut origins: Vec<Option<usize>> = (0..message.len()).map(Some).collect();
+        let mut replaced = vec![false; message.len()];
+        let mut steps = Vec::new();
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            if !pattern.applies(program) {
+                continue;
+            }
+            let mut next_text = String::new();
+            let mut next_origins = Vec::new();
+            let mut end = 0;
+            let mut matches = 0;
+            for caps in pattern.regex.captures_iter(&text) {
+                let found = caps.get(0).expect("group 0 is the whole match");
+                next_text.push_str(&text[end..found.start()]);
+                next_origins.extend_from_slice(&origins[end..found.start()]);
+                for origin in origins[found.range()].iter().flatten() {
+                    replaced[*origin] = true;
+                }
+                caps.expand(&pattern.replacement, &mut next_text);
+                next_origins.resize(next_text.len(), None);
+                end = found.end();
+                matches += 1;
+            }
+            if matches == 0 {
+                continue;
+            }
+            next_text.push_str(&text[end..]);
+            next_origins.extend_from_slice(&origins[end..]);
+            text = next_text;
+            origins = next_origins;
+            steps.push(TraceStep {
+                pattern: index,
+                matches,
+                text: text.clone(),
+            });
+        }
+        Trace {
+            inserted: flagged_ranges(origins.iter().map(Option::is_none)),
+            replaced: flagged_ranges(replaced),
+            text,
+            steps,
         }
-        processed_message
+    }
+
+    /// Returns the names of the loaded patterns in the order they are applied.
+    ///
+    /// Patterns are named after the built-in pattern, the `name` in a structured patterns file,
+    /// or the line they were defined on.
+    pub fn pattern_names(&self) -> Vec<&str> {
+        self.patterns
+            .iter()
+            .map(|pattern| pattern.name.as_str())
+            .collect()
+    }
+
+    /// Returns a fingerprint of the loaded patterns and their replacements.
+    ///
+    /// Comments, blank lines, names, descriptions and test cases in the patterns file do not
+    /// affect the fingerprint, and neither do parameter names since they do not change the
+    /// preprocessed text.
+    pub fn fingerprint(&self) -> String {
+        let scopes: Vec<Option<String>> = self
+            .patterns
+            .iter()
+            .map(|pattern| {
+                (!pattern.applies_to.is_empty())
+                    .then(|| format!("@applies_to {}", pattern.applies_to.join(",")))
+            })
+            .collect();
+        fingerprint(
+            self.patterns
+                .iter()
+                .zip(&scopes)
+                .flat_map(|(pattern, scope)| {
+                    [
+                        Some(pattern.regex.as_str().as_bytes()),
+                        Some(pattern.replacement.as_bytes()),
+                        scope.as_ref().map(|scope| scope.as_bytes()),
+                    ]
+                    .into_iter()
+                    .flatten()
+                }),
+        )
     }
 }
 
@@ -74,4 +707,244 @@ mod tests {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_preprocess_traced() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, r"\[\d+\]: :: [<PID>]:")?;
+        writeln!(file, r"\b(?:\d{{1,3}}\.){{3}}\d{{1,3}}\b :: <IP>")?;
+        let preprocessor = LogPreprocessor::new(file.path().to_str().unwrap())?;
+
+        // Tracing reports the matching patterns and the replaced parts of the message.
+        let message1 = "sshd[12345]: Accepted publickey for user from 192.168.1.1 port 22";
+        let expected1 = "sshd[<PID>]: Accepted publickey for user from <IP> port 22";
+        let trace = preprocessor.preprocess_traced(message1);
+        assert_eq!(trace.text, expected1);
+        assert_eq!(preprocessor.pattern_names(), ["line 1", "line 2"]);
+        assert_eq!(
+            trace
+                .steps
+                .iter()
+                .map(|s| (s.pattern, s.matches))
+                .collect::<Vec<_>>(),
+            [(0, 1), (1, 1)]
+        );
+        assert_eq!(&message1[trace.replaced[0].clone()], "[12345]:");
+        assert_eq!(&message1[trace.replaced[1].clone()], "192.168.1.1");
+        assert_eq!(&expected1[trace.inserted[0].clone()], "[<PID>]:");
+        assert_eq!(&expected1[trace.inserted[1].clone()], "<IP>");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_builtin_directive() -> Result<()> {
+        // Built-in patterns are inserted where the directive appears.
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "{BUILTIN_DIRECTIVE} uuid, quantities")?;
+        writeln!(file, r"<NUM> :: N")?;
+        let preprocessor = LogPreprocessor::new(file.path().to_str().unwrap())?;
+        assert_eq!(
+            preprocessor.preprocess("job 550e8400-e29b-41d4-a716-446655440000 took 3s, 5 retries"),
+            "job <UUID> took <DURATION>, N retries"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_named_placeholders_capture_params() -> Result<()> {
+        let mut plain = NamedTempFile::new()?;
+        writeln!(plain, r"\[(?P<pid>\d+)\]: :: [<PID>]:")?;
+        writeln!(plain, r"\b(?:\d{{1,3}}\.){{3}}\d{{1,3}}\b :: <IP>")?;
+        let mut named = NamedTempFile::new()?;
+        writeln!(named, r"\[(?P<pid>\d+)\]: :: [<PID:pid>]:")?;
+        writeln!(named, r"\b(?:\d{{1,3}}\.){{3}}\d{{1,3}}\b :: <IP:ip>")?;
+        let plain = LogPreprocessor::new(plain.path().to_str().unwrap())?;
+        let named = LogPreprocessor::new(named.path().to_str().unwrap())?;
+
+        let message = "sshd[42]: connection from 10.0.0.1 to 10.0.0.2";
+        let (processed, params) = named.preprocess_with_params(message);
+        assert_eq!(processed, "sshd[<PID>]: connection from <IP> to <IP>");
+        assert_eq!(processed, named.preprocess(message));
+        let params: Vec<(&str, &str)> = params
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            params,
+            [("pid", "42"), ("ip", "10.0.0.1"), ("ip", "10.0.0.2")]
+        );
+        assert!(plain.preprocess_with_params(message).1.is_empty());
+        // Naming the placeholders does not change the preprocessed text.
+        assert_eq!(plain.fingerprint(), named.fingerprint());
+        Ok(())
+    }
+
+    #[test]
+    fn test_structured_patterns_file() -> Result<()> {
+        let write_toml = |text: &str| -> Result<NamedTempFile> {
+            let mut file = tempfile::Builder::new().suffix(".toml").tempfile()?;
+            file.write_all(text.as_bytes())?;
+            Ok(file)
+        };
+        let load_error = |file: &NamedTempFile| -> Result<E> {
+            match LogPreprocessor::new(file.path().to_str().unwrap()) {
+                Ok(_) => Err(E::msg("expected the patterns file to be rejected")),
+                Err(error) => Ok(error),
+            }
+        };
+        let file = write_toml(
+            r#"
+[[pattern]]
+name = "session user"
+description = "User names of sshd sessions"
+regex = 'for user (?P<user>\w+)'
+replacement = "for user <USER:user>"
+flags = "i"
+applies_to = ["sshd"]
+tests = [
+    { input = "sshd[1]: session opened FOR USER alice", expected = "sshd[1]: session opened for user <USER>" },
+    { input = "cron[2]: for user alice", expected = "cron[2]: for user alice" },
+]
+
+[[pattern]]
+name = "disabled"
+regex = 'session'
+replacement = "<SESSION>"
+enabled = false
+
+[[pattern]]
+builtin = "number"
+
+[[pattern.tests]]
+input = "took 12 ms"
+expected = "took <NUM> ms"
+"#,
+        )?;
+        let preprocessor = LogPreprocessor::new(file.path().to_str().unwrap())?;
+        let (processed, params) = preprocessor
+            .preprocess_with_params("Jan  5 10:00:00 web-1 sshd[42]: session opened for user Bob");
+        assert_eq!(
+            processed,
+            "Jan  <NUM> <NUM>:<NUM>:<NUM> web-<NUM> sshd[<NUM>]: session opened for user <USER>"
+        );
+        assert_eq!(params, [("user".to_string(), "Bob".to_string())]);
+        assert_eq!(
+            preprocessor.preprocess("su: for user bob"),
+            "su: for user bob"
+        );
+        assert_eq!(
+            syslog_program("<13>Jan  5 10:00:00 web-1 CRON[7]: job"),
+            Some("CRON")
+        );
+
+        // Failing test cases and malformed entries are reported with their line.
+        let failing = write_toml(
+            "[[pattern]]\nregex = 'a'\nreplacement = 'b'\n\n[[pattern.tests]]\ninput = 'a'\nexpected = 'a'\n",
+        )?;
+        let error = load_error(&failing)?;
+        assert!(
+            error.to_string().contains("line 5: test of pattern"),
+            "{error}"
+        );
+        let malformed = write_toml("[[pattern]]\nname = 'x'\nregex = '('\nreplacement = 'y'\n")?;
+        let error = load_error(&malformed)?;
+        assert!(error.to_string().contains("line 3:"), "{error}");
+        let unknown = write_toml("[[pattern]]\nregex = 'a'\nreplace = 'b'\n")?;
+        let error = load_error(&unknown)?;
+        assert!(
+            error.to_string().contains("line 3: unknown key `replace`"),
+            "{error}"
+        );
+
+        // The line format reports malformed lines too.
+        let mut legacy = NamedTempFile::new()?;
+        writeln!(legacy, r"\d+ :: <NUM>")?;
+        writeln!(legacy, "no separator")?;
+        let error = load_error(&legacy)?;
+        assert!(error.to_string().contains("line 2:"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefilter_matches_sequential_on_corpus() -> Result<()> {
+        let mut file = tempfile::Builder::new().suffix(".toml").tempfile()?;
+        write!(
+            file,
+            r#"
+[[pattern]]
+builtin = "all"
+
+[[pattern]]
+name = "version"
+regex = '<NUM>(?:\.<NUM>)+'
+replacement = "<VERSION>"
+
+[[pattern]]
+name = "sshd user"
+regex = 'for (?:invalid user )?(?P<user>\w+)'
+replacement = "for <USER:user>"
+applies_to = "sshd"
+
+[[pattern]]
+name = "error"
+regex = '\berr(?:or)?\b'
+replacement = "ERROR"
+flags = "i"
+"#
+        )?;
+        let preprocessor = LogPreprocessor::new(file.path().to_str().unwrap())?;
+        assert!(preprocessor.prefilter.is_some());
+
+        let templates = [
+            "Jan {d} 10:{d}:{d} web-{d} sshd[{d}]: Accepted publickey for alice from 10.0.{d}.1 port {d}",
+            "Jan {d} 10:{d}:{d} web-{d} sshd[{d}]: Failed password for invalid user bob",
+            "Jan {d} 10:{d}:{d} web-{d} cron[{d}]: (root) CMD run-parts for alice",
+            "kernel: [{d}.{d}] usb {d}-{d}: new high-speed USB device number {d}",
+            "app v{d}.{d}.{d} started in {d}ms, heap {d}MiB",
+            "Error: request {d} to /api/v{d}/users failed",
+            "GET /index.html 200 {d} bytes",
+            "session {d}abc-{d} opened for user root by (uid=0)",
+            "nothing to replace here",
+            "",
+        ];
+        let mut seed = 7u64;
+        for index in 0..500 {
+            let template = templates[index % templates.len()];
+            let line = template
+                .split("{d}")
+                .enumerate()
+                .map(|(i, part)| {
+                    seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    let number = if i == 0 {
+                        String::new()
+                    } else {
+                        (seed >> 40).to_string()
+                    };
+                    number + part
+                })
+                .collect::<String>();
+
+            assert_eq!(
+                preprocessor.preprocess(&line),
+                preprocessor.preprocess_sequential(&line),
+                "{line}"
+            );
+            let mut params = Vec::new();
+            let sequential = apply_patterns(&preprocessor.patterns, &line, Some(&mut params));
+            assert_eq!(
+                preprocessor.preprocess_with_params(&line),
+                (sequential, params)
+            );
+            let trace = preprocessor.preprocess_traced(&line);
+            assert_eq!(trace.text, preprocessor.preprocess(&line));
+            assert_eq!(
+                trace.steps.last().map_or(line.as_str(), |step| &step.text),
+                trace.text
+            );
+        }
+        Ok(())
+    }
+}
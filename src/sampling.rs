@@ -0,0 +1,94 @@
+/// Advances a SplitMix64 generator and returns its next value.
+///
+/// The generator is tiny and seeded explicitly, so that sampling and index construction give
+/// the same results on every run.
+///
+/// # Arguments
+///
+/// * `state` - The state of the generator.
+pub(crate) fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Keeps a uniform random sample of fixed size from a stream of unknown length (reservoir
+/// sampling).
+pub struct Reservoir<T> {
+    capacity: usize,
+    seen: u64,
+    items: Vec<T>,
+    rng_state: u64,
+}
+
+impl<T> Reservoir<T> {
+    /// Creates an empty reservoir.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - The size of the sample.
+    /// * `seed` - The seed of the random generator choosing the sample.
+    pub fn new(capacity: usize, seed: u64) -> Self {
+        Self {
+            capacity,
+            seen: 0,
+            items: Vec::new(),
+            rng_state: seed,
+        }
+    }
+
+    /// Offers an item of the stream to the sample.
+    ///
+    /// Returns the item that was dropped, either the offered one or one it replaced.
+    pub fn offer(&mut self, item: T) -> Option<T> {
+        self.seen += 1;
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+            return None;
+        }
+        let slot = splitmix64(&mut self.rng_state) % self.seen;
+        if slot < self.capacity as u64 {
+            Some(std::mem::replace(&mut self.items[slot as usize], item))
+        } else {
+            Some(item)
+        }
+    }
+
+    /// Returns the number of items offered so far.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// Returns the sampled items, in no particular order.
+    pub fn into_items(self) -> Vec<T> {
+        self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservoir_samples_uniformly() {
+        let mut counts = [0u32; 10];
+        for seed in 0..2000 {
+            let mut reservoir = Reservoir::new(3, seed);
+            for item in 0..10 {
+                reservoir.offer(item);
+            }
+            assert_eq!(reservoir.seen(), 10);
+            let items = reservoir.into_items();
+            assert_eq!(items.len(), 3);
+            for item in items {
+                counts[item] += 1;
+            }
+        }
+        // Each item is kept in about 3 of 10 runs.
+        for count in counts {
+            assert!((500..700).contains(&count), "{counts:?}");
+        }
+    }
+}
@@ -0,0 +1,119 @@
+/// The placeholder for the variable parts of a template.
+pub const WILDCARD: &str = "<*>";
+
+/// Derives a human-readable template from the messages of a cluster, in the style of Drain:
+/// tokens shared by all messages are kept and the variable parts are replaced with `<*>`.
+///
+/// Messages are split into whitespace-separated tokens and merged into the template one at a
+/// time. Messages with as many tokens as the template are compared position by position, as
+/// long as that keeps at least as many tokens as aligning them; otherwise the tokens are
+/// aligned by their longest common subsequence and every gap becomes a single `<*>`. Returns
+/// `None` if there are no messages.
+///
+/// # Arguments
+///
+/// * `messages` - The (preprocessed) messages of the cluster.
+pub fn extract_template<'a, I>(messages: I) -> Option<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut messages = messages.into_iter();
+    let mut template: Vec<&str> = messages.next()?.split_whitespace().collect();
+    for message in messages {
+        let tokens: Vec<&str> = message.split_whitespace().collect();
+        template = merge(&template, &tokens);
+    }
+    Some(template.join(" "))
+}
+
+/// Merges the tokens of a message into a template.
+fn merge<'a>(template: &[&'a str], tokens: &[&str]) -> Vec<&'a str> {
+    let common = common_subsequence(template, tokens);
+    if template.len() == tokens.len() {
+        let positional = template
+            .iter()
+            .zip(tokens)
+            .filter(|(a, b)| a == b && **a != WILDCARD)
+            .count();
+        if positional >= common.len() {
+            return template
+                .iter()
+                .zip(tokens)
+                .map(|(&a, &b)| if a == b { a } else { WILDCARD })
+                .collect();
+        }
+    }
+
+    let mut merged = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (next_i, next_j) in common.into_iter().chain([(template.len(), tokens.len())]) {
+        if (next_i > i || next_j > j) && merged.last() != Some(&WILDCARD) {
+            merged.push(WILDCARD);
+        }
+        if next_i < template.len() {
+            merged.push(template[next_i]);
+        }
+        (i, j) = (next_i + 1, next_j + 1);
+    }
+    merged
+}
+
+/// Finds the positions of a longest common subsequence of two token lists, ignoring wildcards.
+fn common_subsequence(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    // lengths[i][j] is the length of the longest common subsequence of a[i..] and b[j..].
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] && a[i] != WILDCARD {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let mut pairs = Vec::with_capacity(lengths[0][0]);
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] && a[i] != WILDCARD {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_keeps_constant_tokens() {
+        let template = extract_template([
+            "session opened for alice by root",
+            "session opened for bob by root",
+            "session opened for carol by root",
+        ]);
+        assert_eq!(template.as_deref(), Some("session opened for <*> by root"));
+
+        // Messages of different lengths are aligned.
+        let template = extract_template([
+            "connection from 10.0.0.1 closed",
+            "connection from host example.org closed",
+            "connection reset",
+        ]);
+        assert_eq!(template.as_deref(), Some("connection <*>"));
+        let template = extract_template(["disk sda full", "disk sda1 almost full"]);
+        assert_eq!(template.as_deref(), Some("disk <*> full"));
+
+        assert_eq!(
+            extract_template(["kernel panic"]).as_deref(),
+            Some("kernel panic")
+        );
+        assert_eq!(extract_template([]), None);
+    }
+}
@@ -0,0 +1,440 @@
+use anyhow::{Error as E, Result};
+use chrono::{
+    DateTime, Datelike, FixedOffset, Local, LocalResult, NaiveDate, NaiveDateTime, TimeDelta,
+    TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+/// The names of the built-in timestamp formats, in the order they are tried by default.
+pub const FORMAT_NAMES: [&str; 6] = [
+    "rfc5424",
+    "rfc3339",
+    "syslog",
+    "apache",
+    "epoch-millis",
+    "epoch",
+];
+
+/// A way of locating and parsing the timestamp of a log line.
+#[derive(Debug, Clone)]
+pub enum TimestampFormat {
+    /// Traditional syslog (RFC 3164) prefix, e.g. `Jan  5 10:00:00`, without year or offset.
+    Syslog,
+    /// RFC 5424 syslog header, e.g. `<34>1 2024-01-05T10:00:00.003Z host ...`.
+    Rfc5424,
+    /// An RFC 3339 / ISO 8601 timestamp at the start of the line, e.g. journald `short-iso` output.
+    Rfc3339,
+    /// Seconds since the Unix epoch at the start of the line, optionally with a fraction.
+    EpochSeconds,
+    /// Milliseconds since the Unix epoch at the start of the line.
+    EpochMillis,
+    /// Apache/nginx Common Log Format, e.g. `[05/Jan/2024:10:00:00 +0100]`.
+    ApacheClf,
+    /// A user-supplied regex whose first capture group is parsed with a strftime format.
+    ///
+    /// Formats without a year have it inferred, formats without an offset use the configured time zone.
+    Custom {
+        /// The regex locating the timestamp.
+        regex: Regex,
+        /// The strftime format of the captured text.
+        format: String,
+    },
+}
+
+impl TimestampFormat {
+    /// Creates a custom format from a regex and a strftime format.
+    ///
+    /// # Arguments
+    ///
+    /// * `regex` - A regex whose first capture group (or the whole match) is the timestamp.
+    /// * `format` - The strftime format of the captured text.
+    pub fn custom(regex: &str, format: &str) -> Result<Self> {
+        Ok(Self::Custom {
+            regex: Regex::new(regex)?,
+            format: format.to_string(),
+        })
+    }
+
+    /// Returns all built-in formats in the order they are tried by default.
+    pub fn builtin() -> Vec<Self> {
+        FORMAT_NAMES
+            .iter()
+            .map(|name| name.parse().expect("built-in format names are valid"))
+            .collect()
+    }
+}
+
+impl FromStr for TimestampFormat {
+    type Err = E;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "syslog" | "rfc3164" => Ok(Self::Syslog),
+            "rfc5424" => Ok(Self::Rfc5424),
+            "rfc3339" | "iso8601" => Ok(Self::Rfc3339),
+            "epoch" | "epoch-seconds" => Ok(Self::EpochSeconds),
+            "epoch-millis" => Ok(Self::EpochMillis),
+            "apache" | "clf" => Ok(Self::ApacheClf),
+            _ => Err(E::msg(format!(
+                "Unknown timestamp format '{name}', expected one of: {}",
+                FORMAT_NAMES.join(", ")
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for TimestampFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syslog => write!(f, "syslog"),
+            Self::Rfc5424 => write!(f, "rfc5424"),
+            Self::Rfc3339 => write!(f, "rfc3339"),
+            Self::EpochSeconds => write!(f, "epoch"),
+            Self::EpochMillis => write!(f, "epoch-millis"),
+            Self::ApacheClf => write!(f, "apache"),
+            Self::Custom { regex, format } => write!(f, "custom({regex} => {format})"),
+        }
+    }
+}
+
+/// The time zone used for timestamps that do not carry a UTC offset.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum TimeZoneSpec {
+    /// The time zone of the machine running railog.
+    #[default]
+    Local,
+    /// A fixed UTC offset, e.g. `+01:00`.
+    Fixed(FixedOffset),
+    /// A named IANA time zone with daylight saving rules, e.g. `Europe/Berlin`.
+    Named(Tz),
+}
+
+impl FromStr for TimeZoneSpec {
+    type Err = E;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        if spec.eq_ignore_ascii_case("local") {
+            return Ok(Self::Local);
+        }
+        if spec.eq_ignore_ascii_case("utc") || spec == "Z" {
+            return Ok(Self::Fixed(FixedOffset::east_opt(0).expect("zero offset")));
+        }
+        if spec.starts_with(['+', '-']) {
+            let offset = DateTime::parse_from_str(&format!("2000-01-01 00:00 {spec}"), "%F %R %:z")
+                .or_else(|_| {
+                    DateTime::parse_from_str(&format!("2000-01-01 00:00 {spec}"), "%F %R %z")
+                })
+                .map_err(|_| {
+                    E::msg(format!("Invalid UTC offset '{spec}', expected e.g. +01:00"))
+                })?;
+            return Ok(Self::Fixed(*offset.offset()));
+        }
+        spec.parse::<Tz>()
+            .map(Self::Named)
+            .map_err(|_| E::msg(format!("Unknown time zone '{spec}'")))
+    }
+}
+
+impl TimeZoneSpec {
+    /// Interprets a naive local date and time in this time zone.
+    fn resolve(&self, naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+        fn pick<T: TimeZone>(result: LocalResult<DateTime<T>>) -> Option<DateTime<Utc>> {
+            // During a DST fold the earlier instant is used; times in a DST gap are skipped.
+            result.earliest().map(|t| t.with_timezone(&Utc))
+        }
+        match self {
+            Self::Local => pick(Local.from_local_datetime(&naive)),
+            Self::Fixed(offset) => pick(offset.from_local_datetime(&naive)),
+            Self::Named(tz) => pick(tz.from_local_datetime(&naive)),
+        }
+    }
+
+    /// Returns the current local date and time in this time zone.
+    fn now(&self) -> NaiveDateTime {
+        let now = Utc::now();
+        match self {
+            Self::Local => now.with_timezone(&Local).naive_local(),
+            Self::Fixed(offset) => now.with_timezone(offset).naive_local(),
+            Self::Named(tz) => now.with_timezone(tz).naive_local(),
+        }
+    }
+}
+
+/// A timestamp as found in a log line, before time zone and year are resolved.
+enum Parsed {
+    /// A complete instant.
+    Absolute(DateTime<Utc>),
+    /// A local date and time without offset.
+    Naive(NaiveDateTime),
+    /// A local date and time without offset or year.
+    Yearless {
+        month: u32,
+        day: u32,
+        time: NaiveDateTime,
+    },
+}
+
+/// Extracts timestamps from log lines using a list of formats.
+///
+/// Yearless timestamps (such as the RFC 3164 syslog prefix) get the year of the most recent
+/// timestamp seen, rolling over to the next year when the date jumps back from December to
+/// January, or the current year for the first line. A year that would place the line in the
+/// future is moved back by one.
+pub struct TimestampExtractor {
+    formats: Vec<TimestampFormat>,
+    timezone: TimeZoneSpec,
+    syslog_re: Regex,
+    rfc5424_re: Regex,
+    rfc3339_re: Regex,
+    epoch_re: Regex,
+    epoch_millis_re: Regex,
+    clf_re: Regex,
+    last_inferred: Option<NaiveDateTime>,
+}
+
+impl Default for TimestampExtractor {
+    fn default() -> Self {
+        Self::new(TimestampFormat::builtin(), TimeZoneSpec::default())
+    }
+}
+
+impl TimestampExtractor {
+    /// Creates a new extractor.
+    ///
+    /// # Arguments
+    ///
+    /// * `formats` - The formats to try for each line, in order.
+    /// * `timezone` - The time zone for timestamps without a UTC offset.
+    pub fn new(formats: Vec<TimestampFormat>, timezone: TimeZoneSpec) -> Self {
+        let re = |pattern: &str| Regex::new(pattern).expect("built-in timestamp regex is valid");
+        Self {
+            formats,
+            timezone,
+            syslog_re: re(
+                r"^(?:<\d{1,3}>)?([A-Z][a-z]{2}) {1,2}(\d{1,2}) (\d{2}:\d{2}:\d{2}(?:\.\d{1,9})?)\b",
+            ),
+            rfc5424_re: re(r"^<\d{1,3}>\d{1,2} (\S+)"),
+            rfc3339_re: re(
+                r"^(\d{4}-\d{2}-\d{2}[T ]\d{2}:\d{2}:\d{2}(?:[.,]\d{1,9})?)(Z|[+-]\d{2}:?\d{2})?",
+            ),
+            epoch_re: re(r"^(\d{9,10})(?:\.(\d{1,9}))?\b"),
+            epoch_millis_re: re(r"^(\d{12,13})\b"),
+            clf_re: re(r"\[(\d{2}/[A-Z][a-z]{2}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4})\]"),
+            last_inferred: None,
+        }
+    }
+
+    /// Extracts the timestamp of a log line.
+    ///
+    /// Returns `None` if no format matches.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The raw log line.
+    pub fn extract(&mut self, line: &str) -> Option<DateTime<Utc>> {
+        let parsed = self
+            .formats
+            .iter()
+            .find_map(|format| self.parse(format, line))?;
+        match parsed {
+            Parsed::Absolute(time) => Some(time),
+            Parsed::Naive(naive) => self.timezone.resolve(naive),
+            Parsed::Yearless { month, day, time } => {
+                let naive = self.infer_year(month, day, time)?;
+                self.timezone.resolve(naive)
+            }
+        }
+    }
+
+    /// Tries to parse a line with a single format.
+    fn parse(&self, format: &TimestampFormat, line: &str) -> Option<Parsed> {
+        match format {
+            TimestampFormat::Syslog => {
+                let caps = self.syslog_re.captures(line)?;
+                let text = format!("{} {} 2000 {}", &caps[1], &caps[2], &caps[3]);
+                yearless(NaiveDateTime::parse_from_str(&text, "%b %d %Y %H:%M:%S%.f").ok()?)
+            }
+            TimestampFormat::Rfc5424 => {
+                let caps = self.rfc5424_re.captures(line)?;
+                DateTime::parse_from_rfc3339(&caps[1])
+                    .ok()
+                    .map(|t| Parsed::Absolute(t.with_timezone(&Utc)))
+            }
+            TimestampFormat::Rfc3339 => {
+                let caps = self.rfc3339_re.captures(line)?;
+                let naive = NaiveDateTime::parse_from_str(
+                    &caps[1].replacen(' ', "T", 1).replacen(',', ".", 1),
+                    "%Y-%m-%dT%H:%M:%S%.f",
+                )
+                .ok()?;
+                match caps.get(2).map(|m| m.as_str()) {
+                    None => Some(Parsed::Naive(naive)),
+                    Some("Z") => Some(Parsed::Absolute(naive.and_utc())),
+                    Some(offset) => {
+                        let offset = TimeZoneSpec::from_str(offset).ok()?;
+                        offset.resolve(naive).map(Parsed::Absolute)
+                    }
+                }
+            }
+            TimestampFormat::EpochSeconds => {
+                let caps = self.epoch_re.captures(line)?;
+                let seconds: i64 = caps[1].parse().ok()?;
+                let nanos = caps.get(2).map_or(Some(0), |fraction| {
+                    format!("{:0<9}", fraction.as_str()).parse::<u32>().ok()
+                })?;
+                DateTime::from_timestamp(seconds, nanos).map(Parsed::Absolute)
+            }
+            TimestampFormat::EpochMillis => {
+                let caps = self.epoch_millis_re.captures(line)?;
+                DateTime::from_timestamp_millis(caps[1].parse().ok()?).map(Parsed::Absolute)
+            }
+            TimestampFormat::ApacheClf => {
+                let caps = self.clf_re.captures(line)?;
+                DateTime::parse_from_str(&caps[1], "%d/%b/%Y:%H:%M:%S %z")
+                    .ok()
+                    .map(|t| Parsed::Absolute(t.with_timezone(&Utc)))
+            }
+            TimestampFormat::Custom { regex, format } => {
+                let caps = regex.captures(line)?;
+                let text = caps.get(1).or_else(|| caps.get(0))?.as_str();
+                parse_custom(text, format)
+            }
+        }
+    }
+
+    /// Chooses the year for a timestamp that has none.
+    fn infer_year(&mut self, month: u32, day: u32, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let with_year = |year: i32| {
+            NaiveDate::from_ymd_opt(year, month, day).map(|date| date.and_time(time.time()))
+        };
+        let now = self.timezone.now();
+        let mut inferred = match self.last_inferred {
+            Some(last) => {
+                let candidate = with_year(last.year())?;
+                // Logs are mostly in order, so a jump back of more than half a year is a rollover.
+                if candidate < last - TimeDelta::days(183) {
+                    with_year(last.year() + 1)?
+                } else {
+                    candidate
+                }
+            }
+            None => with_year(now.year()).or_else(|| with_year(now.year() - 1))?,
+        };
+        // Log lines cannot come from the future; allow a day of slack for clock skew.
+        if inferred > now + TimeDelta::days(1) {
+            inferred = with_year(inferred.year() - 1)?;
+        }
+        self.last_inferred = Some(inferred);
+        Some(inferred)
+    }
+}
+
+/// Wraps a timestamp parsed with a placeholder year.
+fn yearless(time: NaiveDateTime) -> Option<Parsed> {
+    Some(Parsed::Yearless {
+        month: time.month(),
+        day: time.day(),
+        time,
+    })
+}
+
+/// Parses a timestamp with a user-supplied strftime format.
+fn parse_custom(text: &str, format: &str) -> Option<Parsed> {
+    if let Ok(time) = DateTime::parse_from_str(text, format) {
+        return Some(Parsed::Absolute(time.with_timezone(&Utc)));
+    }
+    if let Ok(naive) = NaiveDateTime::parse_from_str(text, format) {
+        return Some(Parsed::Naive(naive));
+    }
+    // chrono needs a year to build a date, so supply a leap year placeholder for yearless formats.
+    let naive =
+        NaiveDateTime::parse_from_str(&format!("{text} 2000"), &format!("{format} %Y")).ok()?;
+    yearless(naive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn extractor(timezone: &str) -> TimestampExtractor {
+        TimestampExtractor::new(TimestampFormat::builtin(), timezone.parse().unwrap())
+    }
+
+    #[test]
+    fn test_builtin_formats() {
+        let mut ts = extractor("+01:00");
+        let cases = [
+            (
+                "<34>1 2024-01-05T10:00:00.5Z host app - - - msg",
+                "2024-01-05T10:00:00.5Z",
+            ),
+            (
+                "2024-01-05T10:00:00+0200 host sshd[1]: msg",
+                "2024-01-05T08:00:00Z",
+            ),
+            (
+                "2024-01-05 10:00:00,250 INFO msg",
+                "2024-01-05T09:00:00.25Z",
+            ),
+            ("1704448800.5 msg", "2024-01-05T10:00:00.5Z"),
+            ("1704448800000 msg", "2024-01-05T10:00:00Z"),
+            (
+                r#"127.0.0.1 - - [05/Jan/2024:10:00:00 -0500] "GET / HTTP/1.1" 200 1"#,
+                "2024-01-05T15:00:00Z",
+            ),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(ts.extract(line), Some(utc(expected)), "{line}");
+        }
+        assert_eq!(ts.extract("no timestamp here"), None);
+        assert_eq!(ts.extract("é"), None);
+    }
+
+    #[test]
+    fn test_syslog_year_rollover() {
+        let mut ts = extractor("Europe/Berlin");
+        ts.last_inferred = Some(NaiveDate::from_ymd_opt(2023, 12, 30).unwrap().into());
+        assert_eq!(
+            ts.extract("Dec 31 23:59:59 host kernel: a"),
+            Some(utc("2023-12-31T22:59:59Z"))
+        );
+        assert_eq!(
+            ts.extract("Jan  1 00:00:01 host kernel: b"),
+            Some(utc("2023-12-31T23:00:01Z"))
+        );
+        assert_eq!(
+            ts.extract("Jul  1 12:00:00 host kernel: c"),
+            Some(utc("2024-07-01T10:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_syslog_never_in_future() {
+        let mut ts = extractor("UTC");
+        let time = ts.extract("Jan  1 00:00:00 host kernel: a").unwrap();
+        assert!(time <= Utc::now());
+        let time = ts.extract("Dec 31 23:59:59 host kernel: a").unwrap();
+        assert!(time <= Utc::now() + TimeDelta::days(1));
+    }
+
+    #[test]
+    fn test_custom_format() -> Result<()> {
+        let format = TimestampFormat::custom(r"time=(\S+ \S+)", "%d.%m. %H:%M")?;
+        let mut ts = TimestampExtractor::new(vec![format], "UTC".parse()?);
+        ts.last_inferred = Some(NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().into());
+        assert_eq!(
+            ts.extract("level=info time=05.03. 10:30 msg"),
+            Some(utc("2024-03-05T10:30:00Z"))
+        );
+        Ok(())
+    }
+}
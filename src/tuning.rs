@@ -0,0 +1,117 @@
+use crate::model::DistanceMetric;
+use anyhow::Result;
+use ndarray::Array2;
+
+/// The factors applied to the knee of the k-distance curve to get the candidate epsilons.
+pub const EPSILON_FACTORS: [f32; 5] = [0.5, 0.75, 1.0, 1.5, 2.0];
+
+/// Computes the distance from every point to its `k`-th nearest other point, sorted in
+/// ascending order (the k-distance curve).
+///
+/// DBSCAN counts a point as part of its own neighbourhood, so a point is a core point for
+/// `min_points` exactly when its `min_points - 1`-distance is within epsilon.
+///
+/// # Arguments
+///
+/// * `points` - The points, one per row.
+/// * `k` - The rank of the neighbour, starting at 1 for the nearest one.
+/// * `metric` - The distance metric.
+pub fn k_distances(points: &Array2<f32>, k: usize, metric: DistanceMetric) -> Result<Vec<f64>> {
+    let count = points.nrows();
+    // Only the distances from one point are kept at a time, so memory stays linear.
+    let mut row = Vec::with_capacity(count.saturating_sub(1));
+    let mut curve = Vec::with_capacity(count);
+    for i in 0..count {
+        row.clear();
+        for j in (0..count).filter(|&j| j != i) {
+            row.push(metric.distance(points.row(i), points.row(j))?);
+        }
+        if row.is_empty() {
+            curve.push(f64::INFINITY);
+            continue;
+        }
+        let rank = k.clamp(1, row.len()) - 1;
+        curve.push(*row.select_nth_unstable_by(rank, f64::total_cmp).1);
+    }
+    curve.sort_by(f64::total_cmp);
+    Ok(curve)
+}
+
+/// Finds the knee of an ascending curve: the point furthest below the straight line from its
+/// first to its last point, after scaling both axes to `[0, 1]`.
+///
+/// Before the knee, distances grow slowly as points inside dense clusters are reached; after
+/// it, they shoot up for the noise points. Zero distances, from duplicate messages, are skipped
+/// since epsilon has to be above them anyway. Returns `None` if the rest of the curve is too
+/// short or flat.
+///
+/// # Arguments
+///
+/// * `curve` - The values of the curve, sorted in ascending order.
+pub fn find_knee(curve: &[f64]) -> Option<usize> {
+    let start = curve.partition_point(|&value| value <= 0.0);
+    let positive = &curve[start..];
+    let first = *positive.first()?;
+    let last = *positive.last()?;
+    if positive.len() < 3 || !last.is_finite() || last <= first {
+        return None;
+    }
+    let span = (positive.len() - 1) as f64;
+    positive
+        .iter()
+        .enumerate()
+        .map(|(i, &value)| (i, i as f64 / span - (value - first) / (last - first)))
+        .filter(|&(_, gap)| gap > 0.0)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| start + i)
+}
+
+/// The outcome of clustering a sample with one combination of DBSCAN parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TuneCandidate {
+    /// The DBSCAN minimum cluster size.
+    pub min_points: usize,
+    /// The DBSCAN epsilon.
+    pub epsilon: f32,
+    /// Whether epsilon is the knee of the k-distance curve.
+    pub at_knee: bool,
+    /// The number of clusters found in the sample.
+    pub clusters: usize,
+    /// The fraction of the sample left as noise.
+    pub noise_fraction: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_knee_separates_clusters_from_noise() -> Result<()> {
+        // Two tight groups of 20 points and 5 scattered points.
+        let mut rows = Vec::new();
+        for i in 0..20 {
+            let offset = i as f32 * 0.001;
+            rows.extend([offset, 0.0]);
+            rows.extend([10.0 + offset, 10.0]);
+        }
+        for i in 0..5 {
+            rows.extend([-20.0 * (i + 1) as f32, 30.0]);
+        }
+        let points = Array2::from_shape_vec((45, 2), rows)?;
+
+        let curve = k_distances(&points, 2, DistanceMetric::Euclidean)?;
+        assert_eq!(curve.len(), 45);
+        assert!((curve[0] - 0.001).abs() < 1e-4);
+        let knee = find_knee(&curve).unwrap();
+        assert!(curve[knee] < 0.01, "knee at {}", curve[knee]);
+        assert!(curve[knee + 1..].iter().any(|&d| d > 10.0));
+
+        // Duplicates are skipped.
+        let mut with_duplicates = vec![0.0; 100];
+        with_duplicates.extend(&curve);
+        assert_eq!(find_knee(&with_duplicates), Some(100 + knee));
+        assert_eq!(find_knee(&[1.0, 1.0, 1.0]), None);
+        assert_eq!(find_knee(&[0.5]), None);
+        Ok(())
+    }
+}